@@ -0,0 +1,122 @@
+use std::os::raw::c_int;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::functions::{FunctionFlags, SqlFnOutput};
+use rusqlite::*;
+
+use crate::{Initializer, SyncSqliteConnection};
+
+/// Collects the setup of a `SyncSqliteConnection`. Everything configured here
+/// is applied to the connection of every thread.
+#[derive(Default)]
+pub struct SyncSqliteConnectionBuilder {
+    name: Option<String>,
+    initializers: Vec<Initializer>,
+}
+
+impl SyncSqliteConnectionBuilder {
+    pub fn new() -> Self {
+        SyncSqliteConnectionBuilder::default()
+    }
+
+    /// Opens the database with this name, like `SyncSqliteConnection::open`,
+    /// instead of a new one.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn busy_timeout(self, timeout: Duration) -> Self {
+        self.with_init(move |conn| conn.busy_timeout(timeout))
+    }
+
+    /// Runs `f` on every connection before it is first used.
+    pub fn with_init<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Connection) -> Result<()> + Send + Sync + 'static,
+    {
+        self.initializers.push(Arc::new(f));
+        self
+    }
+
+    /// Registers a scalar function. Each connection gets its own clone of `f`.
+    pub fn register_function<F, T>(
+        self,
+        fn_name: &str,
+        n_arg: c_int,
+        flags: FunctionFlags,
+        f: F,
+    ) -> Self
+    where
+        F: Fn(&functions::Context<'_>) -> Result<T> + Clone + Send + Sync + 'static,
+        T: SqlFnOutput,
+    {
+        let fn_name = fn_name.to_owned();
+        self.with_init(move |conn| {
+            conn.create_scalar_function(fn_name.as_str(), n_arg, flags, f.clone())
+        })
+    }
+
+    pub fn pragma<V>(self, pragma_name: &str, value: V) -> Self
+    where
+        V: ToSql + Send + Sync + 'static,
+    {
+        let pragma_name = pragma_name.to_owned();
+        self.with_init(move |conn| conn.pragma_update(None, pragma_name.as_str(), &value))
+    }
+
+    pub fn build(self) -> Result<SyncSqliteConnection> {
+        let conn = match self.name {
+            Some(name) => SyncSqliteConnection::open(name)?,
+            None => SyncSqliteConnection::new()?,
+        };
+        for initializer in self.initializers {
+            conn.add_initializer(initializer)?;
+        }
+        Result::Ok(conn)
+    }
+}
+
+impl SyncSqliteConnection {
+    pub fn builder() -> SyncSqliteConnectionBuilder {
+        SyncSqliteConnectionBuilder::new()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_builder() {
+        use rusqlite::functions::FunctionFlags;
+
+        let c1 = crate::SyncSqliteConnection::builder()
+            .name("test_builder")
+            .busy_timeout(std::time::Duration::from_millis(250))
+            .pragma("cache_size", -1234)
+            .with_init(|conn| conn.execute_batch("CREATE TEMP TABLE initialized (x INTEGER)"))
+            .register_function("double", 1, FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+                Ok(ctx.get::<i64>(0)? * 2)
+            })
+            .build()
+            .unwrap();
+        assert_eq!(c1.name(), "test_builder");
+
+        let check = || {
+            let (size, timeout, doubled): (i64, i64, i64) = c1
+                .query_row(
+                    "SELECT cache_size, timeout, double(21)
+                     FROM pragma_cache_size, pragma_busy_timeout",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .unwrap();
+            assert_eq!((size, timeout, doubled), (-1234, 250, 42));
+            c1.execute_batch("SELECT * FROM temp.initialized").unwrap();
+        };
+        check();
+        std::thread::scope(|s| {
+            s.spawn(check);
+        });
+    }
+}
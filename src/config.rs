@@ -0,0 +1,418 @@
+use std::sync::Arc;
+
+use rusqlite::config::DbConfig;
+use rusqlite::limits::Limit;
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+/// The values of `PRAGMA journal_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        }
+    }
+}
+
+/// The values of `PRAGMA synchronous`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+impl SyncSqliteConnection {
+    /// Runs `f` on the connection of every thread, including connections
+    /// opened later, e.g. by threads that start using the handle afterwards
+    /// or after `reset_thread_connection`; `f` stays registered for as long
+    /// as the handle and its clones live. A connection can only be used by
+    /// its own thread, so `f` runs on the current thread's connection right
+    /// away and on the others before their next use; exited threads'
+    /// connections are not visited until a new thread takes them over. It
+    /// runs once per connection, also when other threads open their
+    /// connections or register closures at the same time.
+    pub fn for_each_connection<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&Connection) -> Result<()> + Send + Sync + 'static,
+    {
+        self.add_initializer(Arc::new(f))
+    }
+
+    /// Sets a `sqlite3_db_config` flag on the connection of every thread.
+    pub fn set_db_config(&self, config: DbConfig, value: bool) -> Result<()> {
+        self.add_initializer(Arc::new(move |conn| {
+            conn.set_db_config(config, value).map(|_| ())
+        }))
+    }
+
+    /// Makes the connection of every thread report extended result codes,
+    /// e.g. `SQLITE_CONSTRAINT_UNIQUE` instead of `SQLITE_CONSTRAINT`, which
+    /// `extended_code` reads from an error. The setting is per connection, so
+    /// it is reapplied to connections opened later, including the ones a
+    /// thread opens after `reset_thread_connection`.
+    pub fn enable_extended_errors(&self) -> Result<()> {
+        self.add_initializer(Arc::new(|conn| {
+            let rc = unsafe { ffi::sqlite3_extended_result_codes(conn.handle(), 1) };
+            if rc != ffi::SQLITE_OK {
+                return Result::Err(Error::SqliteFailure(ffi::Error::new(rc), None));
+            }
+            Result::Ok(())
+        }))
+    }
+
+    /// Sets a run-time limit on the connection of every thread and returns
+    /// the previous value of the current thread's connection.
+    pub fn set_limit(&self, limit: Limit, value: i32) -> Result<i32> {
+        let previous = self.try_get()?.limit(limit)?;
+        self.add_initializer(Arc::new(move |conn| {
+            conn.set_limit(limit, value).map(|_| ())
+        }))?;
+        Result::Ok(previous)
+    }
+
+    /// Sets `PRAGMA journal_mode` on the connection of every thread. An
+    /// in-memory database only supports `Memory` and `Off`; other modes fail
+    /// with `SQLITE_MISUSE` instead of being silently ignored by SQLite.
+    pub fn set_journal_mode(&self, mode: JournalMode) -> Result<()> {
+        if self.uri.contains("mode=memory")
+            && mode != JournalMode::Memory
+            && mode != JournalMode::Off
+        {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some(format!(
+                    "journal_mode {} is not supported by in-memory databases",
+                    mode.as_str()
+                )),
+            ));
+        }
+
+        self.add_initializer(Arc::new(move |conn| {
+            let actual: String =
+                conn.pragma_update_and_check(None, "journal_mode", mode.as_str(), |row| {
+                    row.get(0)
+                })?;
+            if !actual.eq_ignore_ascii_case(mode.as_str()) {
+                return Result::Err(Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_ERROR),
+                    Some(format!(
+                        "journal_mode {} could not be set, it is {}",
+                        mode.as_str(),
+                        actual
+                    )),
+                ));
+            }
+            Result::Ok(())
+        }))
+    }
+
+    /// Sets `PRAGMA synchronous` on the connection of every thread. It only
+    /// affects databases on disk: in-memory databases are never synced, so
+    /// for them only `Off` is accepted and other levels fail with
+    /// `SQLITE_MISUSE` instead of being silently ignored.
+    pub fn set_synchronous(&self, level: Synchronous) -> Result<()> {
+        if self.uri.contains("mode=memory") && level != Synchronous::Off {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some(format!(
+                    "synchronous {} has no effect on in-memory databases",
+                    level.as_str()
+                )),
+            ));
+        }
+
+        self.add_initializer(Arc::new(move |conn| {
+            conn.pragma_update(None, "synchronous", level.as_str())
+        }))
+    }
+
+    /// Sets `PRAGMA cache_size` on the connection of every thread. A positive
+    /// value is a number of pages, a negative one a size in KiB. Connections
+    /// to a shared-cache database share one page cache, whose size the last
+    /// setting applied decides.
+    pub fn set_cache_size(&self, pages: i32) -> Result<()> {
+        self.add_initializer(Arc::new(move |conn| {
+            conn.pragma_update(None, "cache_size", pages)
+        }))
+    }
+
+    /// Sets `PRAGMA read_uncommitted` on the connection of every thread. With
+    /// it, reads no longer wait for other connections' table locks, at the
+    /// cost of seeing changes of transactions that may still roll back.
+    pub fn set_read_uncommitted(&self, enabled: bool) -> Result<()> {
+        self.add_initializer(Arc::new(move |conn| {
+            conn.pragma_update(None, "read_uncommitted", enabled)
+        }))
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_for_each_connection_runs_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let runs: Vec<Arc<AtomicUsize>> = (0..4).map(|_| Arc::default()).collect();
+        let registered = std::sync::Barrier::new(4);
+        let used = std::sync::Barrier::new(4);
+        std::thread::scope(|s| {
+            for runs in &runs {
+                let runs = runs.clone();
+                s.spawn(|| {
+                    c1.for_each_connection(move |_| {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    })
+                    .unwrap();
+                    registered.wait();
+                    c1.health_check().unwrap();
+                    used.wait();
+                });
+            }
+        });
+
+        // The connections of the four threads and of the current one.
+        c1.health_check().unwrap();
+        for runs in &runs {
+            assert_eq!(runs.load(Ordering::Relaxed), 5);
+        }
+    }
+
+    #[test]
+    fn test_set_limit() {
+        use rusqlite::limits::Limit;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.set_limit(Limit::SQLITE_LIMIT_LENGTH, 100).unwrap();
+        assert_eq!(c1.set_limit(Limit::SQLITE_LIMIT_LENGTH, 100).unwrap(), 100);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let small: Vec<u8> = c1
+                    .query_row("SELECT zeroblob(10)", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(small.len(), 10);
+                assert!(c1
+                    .query_row("SELECT zeroblob(1000)", [], |row| row.get::<_, Vec<u8>>(0))
+                    .is_err());
+            });
+        });
+    }
+
+    #[test]
+    fn test_set_db_config() {
+        use rusqlite::config::DbConfig;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE parent (id INTEGER PRIMARY KEY);
+             CREATE TABLE child (parent_id INTEGER REFERENCES parent (id));",
+        )
+        .unwrap();
+        c1.set_db_config(DbConfig::SQLITE_DBCONFIG_ENABLE_FKEY, true)
+            .unwrap();
+        c1.set_db_config(DbConfig::SQLITE_DBCONFIG_DEFENSIVE, true)
+            .unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert!(c1.db_config(DbConfig::SQLITE_DBCONFIG_DEFENSIVE).unwrap());
+                assert!(c1.execute("INSERT INTO child VALUES (1)", []).is_err());
+            });
+        });
+    }
+
+    #[test]
+    fn test_enable_extended_errors() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER UNIQUE); INSERT INTO t VALUES (1);")
+            .unwrap();
+        // Starts from connections that report primary codes only.
+        c1.for_each_connection(|conn| {
+            unsafe { rusqlite::ffi::sqlite3_extended_result_codes(conn.handle(), 0) };
+            Ok(())
+        })
+        .unwrap();
+        let insert = || c1.execute("INSERT INTO t VALUES (1)", []).unwrap_err();
+        assert_eq!(
+            crate::extended_code(&insert()),
+            Some(rusqlite::ffi::SQLITE_CONSTRAINT)
+        );
+
+        let opened = std::sync::Barrier::new(2);
+        let enabled = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.health_check().unwrap();
+                opened.wait();
+                enabled.wait();
+                assert_eq!(
+                    crate::extended_code(&insert()),
+                    Some(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+                );
+            });
+
+            opened.wait();
+            c1.enable_extended_errors().unwrap();
+            enabled.wait();
+        });
+        assert_eq!(
+            crate::extended_code(&insert()),
+            Some(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+        );
+        c1.reset_thread_connection();
+        assert_eq!(
+            crate::extended_code(&insert()),
+            Some(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+        );
+    }
+
+    #[test]
+    fn test_set_read_uncommitted() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let c2 = crate::SyncSqliteConnection::open(c1.name().clone()).unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        c1.set_read_uncommitted(true).unwrap();
+
+        let tx = c1.transaction().unwrap();
+        tx.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let count: i64 = c1
+                    .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(count, 1);
+                assert!(c2
+                    .query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0))
+                    .is_err());
+            });
+        });
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_set_journal_mode() {
+        use crate::{JournalMode, Synchronous};
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.set_journal_mode(JournalMode::Memory).unwrap();
+        c1.set_synchronous(Synchronous::Off).unwrap();
+        assert!(c1.set_journal_mode(JournalMode::Wal).is_err());
+        let err = c1.set_synchronous(Synchronous::Full).unwrap_err();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::ApiMisuse)
+        );
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mode: String = c1
+                    .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(mode, "memory");
+                let level: i64 = c1
+                    .query_row("PRAGMA synchronous", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(level, 0);
+            });
+        });
+    }
+
+    #[test]
+    fn test_set_synchronous_file() {
+        use crate::Synchronous;
+
+        let path = std::env::temp_dir().join(format!(
+            "inmemory-sqlite-{}-synchronous.db",
+            std::process::id()
+        ));
+        let _ignore = std::fs::remove_file(&path);
+
+        let c1 = crate::SyncSqliteConnection::new_file_shared(&path).unwrap();
+        c1.set_synchronous(Synchronous::Extra).unwrap();
+        let level: i64 = c1
+            .query_row("PRAGMA synchronous", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(level, 3);
+        c1.shutdown().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_set_cache_size() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let cache_size = || -> i64 {
+            c1.query_row("PRAGMA cache_size", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        c1.set_cache_size(500).unwrap();
+        assert_eq!(cache_size(), 500);
+        c1.set_cache_size(-8192).unwrap();
+        assert_eq!(cache_size(), -8192);
+        assert_eq!(
+            std::thread::scope(|s| s.spawn(cache_size).join().unwrap()),
+            -8192
+        );
+    }
+
+    #[test]
+    fn test_for_each_connection() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let opened = std::sync::Barrier::new(2);
+        let applied = std::sync::Barrier::new(2);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.health_check().unwrap();
+                opened.wait();
+                applied.wait();
+                let size: i64 = c1
+                    .query_row("PRAGMA cache_size", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(size, -4321);
+            });
+
+            opened.wait();
+            c1.for_each_connection(|conn| conn.pragma_update(None, "cache_size", -4321))
+                .unwrap();
+            applied.wait();
+        });
+
+        let size: i64 = c1
+            .query_row("PRAGMA cache_size", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(size, -4321);
+    }
+}
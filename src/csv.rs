@@ -0,0 +1,151 @@
+use std::io::{Read, Write};
+
+use rusqlite::types::ValueRef;
+use rusqlite::*;
+
+use crate::error::write_error;
+use crate::{quote_identifier, SyncSqliteConnection, SyncStatement};
+
+fn csv_error(err: ::csv::Error) -> Error {
+    Error::ToSqlConversionFailure(Box::new(err))
+}
+
+impl SyncSqliteConnection {
+    /// Inserts the records of the CSV data into an existing table in one
+    /// transaction and returns how many there were. Fields are inserted as
+    /// TEXT and converted by the column affinity. With `has_header`, the
+    /// first record names the columns to fill; otherwise records fill the
+    /// columns in order.
+    pub fn import_csv<R: Read>(&self, table: &str, reader: R, has_header: bool) -> Result<usize> {
+        let mut reader = ::csv::ReaderBuilder::new()
+            .has_headers(has_header)
+            .from_reader(reader);
+        let columns = if has_header {
+            let headers = reader.headers().map_err(csv_error)?;
+            let names: Vec<String> = headers.iter().map(quote_identifier).collect();
+            format!(" ({})", names.join(", "))
+        } else {
+            String::new()
+        };
+
+        let tx = self.transaction()?;
+        let mut count = 0;
+        for record in reader.records() {
+            let record = record.map_err(csv_error)?;
+            let placeholders = vec!["?"; record.len()].join(", ");
+            let sql = format!(
+                "INSERT INTO {}{} VALUES ({})",
+                quote_identifier(table),
+                columns,
+                placeholders
+            );
+            tx.prepare_cached(&sql)?
+                .execute(params_from_iter(record.iter()))?;
+            count += 1;
+        }
+        tx.commit()?;
+        Result::Ok(count)
+    }
+}
+
+impl<'conn> SyncStatement<'conn> {
+    /// Writes the rows of the query as CSV, preceded by the column names if
+    /// `header` is set, and returns the number of rows. NULL is written as an
+    /// empty field and BLOBs as their raw bytes.
+    pub fn export_csv<W: Write, P: Params>(
+        &self,
+        params: P,
+        writer: W,
+        header: bool,
+    ) -> Result<usize> {
+        let mut writer = ::csv::Writer::from_writer(writer);
+        let names = self.column_names();
+        let column_count = names.len();
+        let mut rows = self.query(params)?;
+        if header {
+            writer.write_record(&names).map_err(write_error)?;
+        }
+
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            let mut record = ::csv::ByteRecord::new();
+            for idx in 0..column_count {
+                match row.get_ref(idx)? {
+                    ValueRef::Null => record.push_field(b""),
+                    ValueRef::Integer(i) => record.push_field(i.to_string().as_bytes()),
+                    ValueRef::Real(f) => record.push_field(f.to_string().as_bytes()),
+                    ValueRef::Text(text) => record.push_field(text),
+                    ValueRef::Blob(blob) => record.push_field(blob),
+                }
+            }
+            writer.write_byte_record(&record).map_err(write_error)?;
+            count += 1;
+        }
+        writer.flush().map_err(write_error)?;
+        Result::Ok(count)
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_import_csv() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE people (id INTEGER, name TEXT, note TEXT)")
+            .unwrap();
+
+        let data = "name,id,note\n\"Doe, Jane\",1,\"said \"\"hi\"\"\"\nBob,2,\n";
+        assert_eq!(c1.import_csv("people", data.as_bytes(), true).unwrap(), 2);
+        assert_eq!(
+            c1.import_csv("people", "3,Eve,x\n".as_bytes(), false)
+                .unwrap(),
+            1
+        );
+
+        let rows: Vec<(i64, String, String)> = c1
+            .prepare("SELECT id, name, note FROM people ORDER BY id")
+            .unwrap()
+            .query_collect([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (1, "Doe, Jane".to_owned(), "said \"hi\"".to_owned()),
+                (2, "Bob".to_owned(), String::new()),
+                (3, "Eve".to_owned(), "x".to_owned()),
+            ]
+        );
+
+        assert!(c1
+            .import_csv("people", "name,missing\na,b\n".as_bytes(), true)
+            .is_err());
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM people", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE people (id INTEGER, name TEXT);
+             INSERT INTO people VALUES (1, 'Doe, Jane'), (2, NULL), (3, 'Bob');",
+        )
+        .unwrap();
+
+        let stmt = c1
+            .prepare("SELECT id, name FROM people WHERE id > ? ORDER BY id")
+            .unwrap();
+        let mut out = Vec::new();
+        assert_eq!(stmt.export_csv([0], &mut out, true).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id,name\n1,\"Doe, Jane\"\n2,\n3,Bob\n"
+        );
+
+        let mut out = Vec::new();
+        assert_eq!(stmt.export_csv([2], &mut out, false).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), "3,Bob\n");
+    }
+}
@@ -0,0 +1,138 @@
+use std::error;
+use std::fmt;
+use std::os::raw::c_int;
+
+use rusqlite::{ffi, Error, ErrorCode};
+
+// Failures of the Write a dump or export goes to.
+pub(crate) fn write_error<E: ToString>(err: E) -> Error {
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_IOERR), Some(err.to_string()))
+}
+
+/// Whether the error is a lock conflict with another connection
+/// (`SQLITE_BUSY` or `SQLITE_LOCKED`), which may go away if the operation is
+/// retried.
+pub fn is_busy(err: &Error) -> bool {
+    match err.sqlite_error_code() {
+        Some(code) => code == ErrorCode::DatabaseBusy || code == ErrorCode::DatabaseLocked,
+        None => false,
+    }
+}
+
+/// Whether the error is a violated UNIQUE, NOT NULL, CHECK, FOREIGN KEY or
+/// other constraint.
+pub fn is_constraint_violation(err: &Error) -> bool {
+    err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation)
+}
+
+/// The extended result code of an error from SQLite, e.g.
+/// `SQLITE_CONSTRAINT_UNIQUE`, or `None` for errors raised by rusqlite.
+/// rusqlite enables extended result codes on every connection it opens;
+/// `enable_extended_errors` keeps them on for all connections of a handle.
+pub fn extended_code(err: &Error) -> Option<c_int> {
+    match err {
+        Error::SqliteFailure(e, _) => Some(e.extended_code),
+        _ => None,
+    }
+}
+
+/// Errors of operations that, besides failing in SQLite, can find that the
+/// shared database they refer to is gone or that a statement did not have
+/// the expected effect. Only operations that reach a
+/// database by name, like `SyncSqliteConnection::open_existing`, can: a
+/// handle keeps at least one connection to its database open until it is
+/// dropped, so methods of a live handle such as `try_get`, `fork` or
+/// `health_check` never find it evicted.
+#[derive(Debug)]
+pub enum InMemoryError {
+    Sqlite(Error),
+    /// No `SyncSqliteConnection` keeps the named database alive, so SQLite
+    /// has freed it, or it was never opened.
+    DatabaseEvicted(String),
+    /// A statement run by `SyncStatement::execute_expect` changed a number
+    /// of rows other than the expected one.
+    UnexpectedChangedRows {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for InMemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InMemoryError::Sqlite(err) => err.fmt(f),
+            InMemoryError::DatabaseEvicted(name) => {
+                write!(f, "the in-memory database {} has been evicted", name)
+            }
+            InMemoryError::UnexpectedChangedRows { expected, actual } => {
+                write!(f, "expected {} changed rows, got {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl error::Error for InMemoryError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InMemoryError::Sqlite(err) => Some(err),
+            InMemoryError::DatabaseEvicted(_) | InMemoryError::UnexpectedChangedRows { .. } => None,
+        }
+    }
+}
+
+impl From<Error> for InMemoryError {
+    fn from(err: Error) -> Self {
+        InMemoryError::Sqlite(err)
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_constraint_violation() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER UNIQUE); INSERT INTO t VALUES (1);")
+            .unwrap();
+
+        let err = c1.execute("INSERT INTO t VALUES (1)", []).unwrap_err();
+        assert!(crate::is_constraint_violation(&err));
+        assert!(!crate::is_busy(&err));
+        assert_eq!(
+            crate::extended_code(&err),
+            Some(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+        );
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let err = c1.execute("INSERT INTO t VALUES (1)", []).unwrap_err();
+                assert_eq!(
+                    crate::extended_code(&err),
+                    Some(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE)
+                );
+            });
+        });
+
+        let err = c1.execute("INSERT INTO t VALUES (?)", [1, 2]).unwrap_err();
+        assert!(!crate::is_constraint_violation(&err));
+        assert_eq!(crate::extended_code(&err), None);
+    }
+
+    #[test]
+    fn test_busy() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let tx = c1
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let err = c1.execute("INSERT INTO t VALUES (1)", []).unwrap_err();
+                assert!(crate::is_busy(&err));
+                assert!(!crate::is_constraint_violation(&err));
+                assert!(crate::extended_code(&err).is_some());
+            });
+        });
+        tx.rollback().unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+impl SyncSqliteConnection {
+    /// Loads the SQLite extension at `path` into the connection of every
+    /// thread, calling `entry_point` or the default entry point SQLite
+    /// derives from the file name. Extension loading is only enabled while
+    /// the extension loads, so SQL cannot load others with
+    /// `load_extension()`.
+    ///
+    /// # Safety
+    ///
+    /// Loading an extension runs its code in every connection, see
+    /// `rusqlite::Connection::load_extension`.
+    pub unsafe fn load_extension(&self, path: &Path, entry_point: Option<&str>) -> Result<()> {
+        let path = path.to_owned();
+        let entry_point = entry_point.map(str::to_owned);
+        self.add_initializer(Arc::new(move |conn| unsafe {
+            conn.load_extension_enable()?;
+            let result = conn.load_extension(&path, entry_point.as_deref());
+            conn.load_extension_disable()?;
+            result
+        }))
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_load_extension() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let missing = std::env::temp_dir().join("inmemory-sqlite-missing-extension");
+        assert!(unsafe { c1.load_extension(&missing, None) }.is_err());
+
+        // Neither the failed load nor the registration left extension
+        // loading enabled on any thread.
+        let check = || {
+            assert!(c1
+                .query_row("SELECT load_extension(?)", [missing.to_str()], |_| Ok(()))
+                .is_err());
+            c1.health_check().unwrap();
+        };
+        check();
+        std::thread::scope(|s| {
+            s.spawn(check);
+        });
+
+        // With INMEMORY_SQLITE_EXTENSION naming a loadable extension and
+        // INMEMORY_SQLITE_EXTENSION_SQL a query using it, check that every
+        // thread's connection gets it.
+        let (Some(path), Some(sql)) = (
+            std::env::var_os("INMEMORY_SQLITE_EXTENSION"),
+            std::env::var("INMEMORY_SQLITE_EXTENSION_SQL").ok(),
+        ) else {
+            return;
+        };
+        unsafe { c1.load_extension(std::path::Path::new(&path), None) }.unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| c1.execute_batch(&sql).unwrap());
+        });
+    }
+}
@@ -0,0 +1,446 @@
+use std::cmp::Ordering;
+use std::os::raw::c_int;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+
+use rusqlite::functions::{Aggregate, Context, FunctionFlags, SqlFnOutput, WindowAggregate};
+use rusqlite::vtab::{Module, VTab};
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+// A `Module` is a table of C callbacks without any state, so the same
+// module can be registered from every thread.
+struct SharedModule<T: VTab<'static> + 'static>(&'static Module<'static, T>);
+
+unsafe impl<T: VTab<'static> + 'static> Send for SharedModule<T> {}
+unsafe impl<T: VTab<'static> + 'static> Sync for SharedModule<T> {}
+
+impl SyncSqliteConnection {
+    /// Registers a scalar function on the connection of every thread. `f` gets
+    /// the connection the calling statement runs on, so it can run queries of
+    /// its own that see the same data, including changes of the thread's open
+    /// transaction.
+    pub fn register_function_with_connection<F, T>(
+        &self,
+        fn_name: &str,
+        n_arg: c_int,
+        flags: FunctionFlags,
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Connection, &Context<'_>) -> Result<T> + Send + Sync + 'static,
+        T: SqlFnOutput,
+    {
+        let fn_name = fn_name.to_owned();
+        let f = Arc::new(f);
+        self.add_initializer(Arc::new(move |conn| {
+            let f = f.clone();
+            conn.create_scalar_function(fn_name.as_str(), n_arg, flags, move |ctx| {
+                // The connection is only lent to `f` for the call; a
+                // `&Connection` cannot leave the thread.
+                let conn = unsafe { ctx.get_connection()? };
+                f(&conn, ctx)
+            })
+        }))
+    }
+
+    /// Registers an aggregate function on the connection of every thread.
+    /// Each connection gets its own clone of `aggr`.
+    pub fn register_aggregate_function<A, D, T>(
+        &self,
+        fn_name: &str,
+        n_arg: c_int,
+        flags: FunctionFlags,
+        aggr: D,
+    ) -> Result<()>
+    where
+        A: RefUnwindSafe + UnwindSafe,
+        D: Aggregate<A, T> + Clone + Send + Sync + 'static,
+        T: SqlFnOutput,
+    {
+        let fn_name = fn_name.to_owned();
+        self.add_initializer(Arc::new(move |conn| {
+            conn.create_aggregate_function(fn_name.as_str(), n_arg, flags, aggr.clone())
+        }))
+    }
+
+    /// Registers a window function on the connection of every thread. Each
+    /// connection gets its own clone of `aggr`. Window functions need SQLite
+    /// 3.25.0 or later.
+    pub fn register_window_function<A, W, T>(
+        &self,
+        fn_name: &str,
+        n_arg: c_int,
+        flags: FunctionFlags,
+        aggr: W,
+    ) -> Result<()>
+    where
+        A: RefUnwindSafe + UnwindSafe,
+        W: WindowAggregate<A, T> + Clone + Send + Sync + 'static,
+        T: SqlFnOutput,
+    {
+        let fn_name = fn_name.to_owned();
+        self.add_initializer(Arc::new(move |conn| {
+            conn.create_window_function(fn_name.as_str(), n_arg, flags, aggr.clone())
+        }))
+    }
+
+    /// Registers a collation sequence on the connection of every thread.
+    pub fn register_collation<F>(&self, name: &str, f: F) -> Result<()>
+    where
+        F: Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    {
+        let name = name.to_owned();
+        let f = Arc::new(f);
+        self.add_initializer(Arc::new(move |conn| {
+            let f = f.clone();
+            conn.create_collation(name.as_str(), move |a, b| f(a, b))
+        }))
+    }
+
+    /// Registers a virtual table module on the connection of every thread.
+    /// Each connection gets its own clone of `aux`.
+    pub fn register_module<T>(
+        &self,
+        module_name: &str,
+        module: &'static Module<'static, T>,
+        aux: Option<T::Aux>,
+    ) -> Result<()>
+    where
+        T: VTab<'static> + 'static,
+        T::Aux: Clone,
+    {
+        let module_name = module_name.to_owned();
+        let module = SharedModule(module);
+        self.add_initializer(Arc::new(move |conn| {
+            conn.create_module(module_name.as_str(), module.0, aux.clone())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    #[derive(Clone)]
+    struct Product;
+
+    impl rusqlite::functions::Aggregate<i64, i64> for Product {
+        fn init(&self, _: &mut rusqlite::functions::Context<'_>) -> rusqlite::Result<i64> {
+            Ok(1)
+        }
+
+        fn step(
+            &self,
+            ctx: &mut rusqlite::functions::Context<'_>,
+            product: &mut i64,
+        ) -> rusqlite::Result<()> {
+            *product *= ctx.get::<i64>(0)?;
+            Ok(())
+        }
+
+        fn finalize(
+            &self,
+            _: &mut rusqlite::functions::Context<'_>,
+            product: Option<i64>,
+        ) -> rusqlite::Result<i64> {
+            Ok(product.unwrap_or(1))
+        }
+    }
+
+    #[derive(Clone)]
+    struct Sum;
+
+    impl rusqlite::functions::Aggregate<i64, Option<i64>> for Sum {
+        fn init(&self, _: &mut rusqlite::functions::Context<'_>) -> rusqlite::Result<i64> {
+            Ok(0)
+        }
+
+        fn step(
+            &self,
+            ctx: &mut rusqlite::functions::Context<'_>,
+            sum: &mut i64,
+        ) -> rusqlite::Result<()> {
+            *sum += ctx.get::<i64>(0)?;
+            Ok(())
+        }
+
+        fn finalize(
+            &self,
+            _: &mut rusqlite::functions::Context<'_>,
+            sum: Option<i64>,
+        ) -> rusqlite::Result<Option<i64>> {
+            Ok(sum)
+        }
+    }
+
+    impl rusqlite::functions::WindowAggregate<i64, Option<i64>> for Sum {
+        fn value(&self, sum: Option<&mut i64>) -> rusqlite::Result<Option<i64>> {
+            Ok(sum.copied())
+        }
+
+        fn inverse(
+            &self,
+            ctx: &mut rusqlite::functions::Context<'_>,
+            sum: &mut i64,
+        ) -> rusqlite::Result<()> {
+            *sum -= ctx.get::<i64>(0)?;
+            Ok(())
+        }
+    }
+
+    // upto(n): the integers 1 to n, like a minimal generate_series.
+    #[repr(C)]
+    struct Upto {
+        base: rusqlite::ffi::sqlite3_vtab,
+    }
+
+    unsafe impl<'vtab> rusqlite::vtab::VTab<'vtab> for Upto {
+        type Aux = ();
+        type Cursor = UptoCursor;
+
+        fn connect(
+            _: &mut rusqlite::vtab::VTabConnection,
+            _: Option<&()>,
+            _: &[u8],
+            _: &[u8],
+            _: &[u8],
+            _: &[&[u8]],
+        ) -> rusqlite::Result<(std::borrow::Cow<'static, std::ffi::CStr>, Self)> {
+            let vtab = Upto {
+                base: rusqlite::ffi::sqlite3_vtab::default(),
+            };
+            Ok((
+                std::borrow::Cow::Borrowed(
+                    std::ffi::CStr::from_bytes_with_nul(b"CREATE TABLE x(value, stop HIDDEN)\0")
+                        .unwrap(),
+                ),
+                vtab,
+            ))
+        }
+
+        fn best_index(&self, info: &mut rusqlite::vtab::IndexInfo) -> rusqlite::Result<bool> {
+            let stop = info.constraints().position(|constraint| {
+                constraint.column() == 1
+                    && constraint.is_usable()
+                    && constraint.operator()
+                        == rusqlite::vtab::IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+            });
+            match stop {
+                Some(stop) => {
+                    let mut usage = info.constraint_usage(stop);
+                    usage.set_argv_index(1);
+                    usage.set_omit(true);
+                    info.set_idx_num(1);
+                }
+                None => info.set_idx_num(0),
+            }
+            Ok(true)
+        }
+
+        fn open(&mut self) -> rusqlite::Result<UptoCursor> {
+            Ok(UptoCursor::default())
+        }
+    }
+
+    #[derive(Default)]
+    #[repr(C)]
+    struct UptoCursor {
+        base: rusqlite::ffi::sqlite3_vtab_cursor,
+        value: i64,
+        stop: i64,
+    }
+
+    unsafe impl rusqlite::vtab::VTabCursor for UptoCursor {
+        fn filter(
+            &mut self,
+            idx_num: std::os::raw::c_int,
+            _: Option<&str>,
+            args: &rusqlite::vtab::Filters<'_>,
+        ) -> rusqlite::Result<()> {
+            self.stop = if idx_num == 1 { args.get(0)? } else { 0 };
+            self.value = 1;
+            Ok(())
+        }
+
+        fn next(&mut self) -> rusqlite::Result<()> {
+            self.value += 1;
+            Ok(())
+        }
+
+        fn eof(&self) -> bool {
+            self.value > self.stop
+        }
+
+        fn column(
+            &self,
+            ctx: &mut rusqlite::vtab::Context,
+            i: std::os::raw::c_int,
+        ) -> rusqlite::Result<()> {
+            match i {
+                0 => ctx.set_result(&self.value),
+                _ => ctx.set_result(&self.stop),
+            }
+        }
+
+        fn rowid(&self) -> rusqlite::Result<i64> {
+            Ok(self.value)
+        }
+    }
+
+    const UPTO: rusqlite::vtab::Module<'static, Upto> =
+        rusqlite::vtab::Module::eponymous_only_module();
+
+    #[test]
+    fn test_register_module() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.register_module("upto", &UPTO, None).unwrap();
+
+        let values: Vec<i64> = std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.prepare("SELECT value FROM upto(3)")
+                    .unwrap()
+                    .query_map(rusqlite::params![], |row| row.get(0))
+                    .unwrap()
+                    .collect::<rusqlite::Result<_>>()
+                    .unwrap()
+            })
+            .join()
+            .unwrap()
+        });
+        assert_eq!(values, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_register_aggregate_function() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (g TEXT, x INTEGER);
+             INSERT INTO t VALUES ('a', 2), ('a', 3), ('b', 4), ('b', 5), ('b', 6);",
+        )
+        .unwrap();
+        c1.register_aggregate_function(
+            "product",
+            1,
+            rusqlite::functions::FunctionFlags::SQLITE_UTF8
+                | rusqlite::functions::FunctionFlags::SQLITE_DETERMINISTIC,
+            Product,
+        )
+        .unwrap();
+
+        let products = || -> Vec<(String, i64)> {
+            c1.prepare("SELECT g, product(x) FROM t GROUP BY g ORDER BY g")
+                .unwrap()
+                .query_collect([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+        };
+
+        let here = products();
+        let there = std::thread::scope(|s| s.spawn(products).join().unwrap());
+        assert_eq!(here, vec![("a".to_owned(), 6), ("b".to_owned(), 120)]);
+        assert_eq!(here, there);
+    }
+
+    #[test]
+    fn test_register_window_function() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (x INTEGER);
+             INSERT INTO t VALUES (1), (2), (3), (4);",
+        )
+        .unwrap();
+        c1.register_window_function(
+            "mysum",
+            1,
+            rusqlite::functions::FunctionFlags::SQLITE_UTF8,
+            Sum,
+        )
+        .unwrap();
+
+        let running_sums = || -> Vec<i64> {
+            c1.prepare(
+                "SELECT mysum(x) OVER (ORDER BY x ROWS BETWEEN 1 PRECEDING AND CURRENT ROW) FROM t",
+            )
+            .unwrap()
+            .query_map(rusqlite::params![], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+        };
+
+        let here = running_sums();
+        let there = std::thread::scope(|s| s.spawn(running_sums).join().unwrap());
+        assert_eq!(here, vec![1, 3, 5, 7]);
+        assert_eq!(here, there);
+
+        let totals: Vec<i64> = c1
+            .prepare("SELECT mysum(x) OVER (ORDER BY x) FROM t")
+            .unwrap()
+            .query_collect([], |row| row.get(0))
+            .unwrap();
+        assert_eq!(totals, vec![1, 3, 6, 10]);
+    }
+
+    #[test]
+    fn test_register_function_with_connection() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE prices (name TEXT, price INTEGER);
+             INSERT INTO prices VALUES ('a', 3), ('b', 5);",
+        )
+        .unwrap();
+        c1.register_function_with_connection(
+            "price_of",
+            1,
+            rusqlite::functions::FunctionFlags::SQLITE_UTF8,
+            |conn, ctx| {
+                conn.query_row(
+                    "SELECT price FROM prices WHERE name = ?",
+                    [ctx.get::<String>(0)?],
+                    |row| row.get::<_, i64>(0),
+                )
+            },
+        )
+        .unwrap();
+
+        let doubled = |name: &str| -> i64 {
+            c1.query_row("SELECT price_of(?) * 2", [name], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(doubled("a"), 6);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(doubled("b"), 10);
+
+                let tx = c1.transaction().unwrap();
+                tx.execute("INSERT INTO prices VALUES ('c', 7)", [])
+                    .unwrap();
+                let price: i64 = tx
+                    .query_row("SELECT price_of('c')", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(price, 7);
+            });
+        });
+    }
+
+    #[test]
+    fn test_register_collation() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (s TEXT); INSERT INTO t VALUES ('b'), ('a'), ('c');")
+            .unwrap();
+        c1.register_collation("myrev", |a, b| b.cmp(a)).unwrap();
+
+        let check = || {
+            let values: Vec<String> = c1
+                .prepare("SELECT s FROM t ORDER BY s COLLATE myrev")
+                .unwrap()
+                .query_collect([], |row| row.get(0))
+                .unwrap();
+            assert_eq!(values, vec!["c", "b", "a"]);
+        };
+        check();
+        std::thread::scope(|s| {
+            s.spawn(check);
+        });
+    }
+}
@@ -0,0 +1,46 @@
+use std::ops::Deref;
+
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+/// The current thread's connection, looked up once for a series of
+/// operations. It derefs to `Connection` and cannot leave the thread.
+pub struct ConnGuard<'conn> {
+    conn: &'conn Connection,
+}
+
+impl<'conn> Deref for ConnGuard<'conn> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl SyncSqliteConnection {
+    pub fn acquire(&self) -> Result<ConnGuard<'_>> {
+        Result::Ok(ConnGuard {
+            conn: self.try_get()?,
+        })
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_acquire() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let conn = c1.acquire().unwrap();
+        let mut insert = conn.prepare("INSERT INTO t VALUES (?)").unwrap();
+        for i in 0..1000 {
+            insert.execute([i]).unwrap();
+        }
+        let sum: i64 = conn
+            .query_row("SELECT sum(x) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(sum, 999 * 1000 / 2);
+    }
+}
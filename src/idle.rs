@@ -0,0 +1,411 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+// Stored in `Slot::last_used` while its connections are being closed, and
+// once all of them are.
+const CLOSING: u64 = u64::MAX;
+const CLOSED: u64 = u64::MAX - 1;
+
+// The connections a single thread has opened, the last one being the one in
+// use. Besides the thread, the idle reaper reaches them through IdleSlots.
+#[derive(Default)]
+pub(crate) struct Slot {
+    // Boxed so that borrows of them survive the Vec growing.
+    #[allow(clippy::vec_box)]
+    connections: Mutex<Vec<Box<Connection>>>,
+    // When the thread last used the handle, in milliseconds since the handle
+    // was created, or CLOSING or CLOSED.
+    last_used: AtomicU64,
+    // Number of statements prepared on the connections through SyncStatement.
+    statements: AtomicUsize,
+}
+
+impl Slot {
+    #[allow(clippy::vec_box)]
+    pub(crate) fn connections(&self) -> MutexGuard<'_, Vec<Box<Connection>>> {
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Counts a statement prepared on the connections of a slot for as long as it
+// lives, which keeps the slot from being reaped.
+pub(crate) struct StatementCount(Arc<Slot>);
+
+impl StatementCount {
+    pub(crate) fn new(slot: Arc<Slot>) -> StatementCount {
+        slot.statements.fetch_add(1, Ordering::AcqRel);
+        StatementCount(slot)
+    }
+}
+
+impl Drop for StatementCount {
+    fn drop(&mut self) {
+        self.0.statements.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Which connections closing idle ones has to leave open for the database to
+// survive.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Retain {
+    // Each connection has its own database, as with CacheMode::Private.
+    Every,
+    // The in-memory database lives as long as any connection to it.
+    One,
+    // The database is a file, which outlives the connections.
+    None,
+}
+
+// The slots of every thread that used the handle, and how many connections
+// they hold open.
+pub(crate) struct IdleSlots {
+    epoch: Instant,
+    slots: Mutex<Vec<Arc<Slot>>>,
+    pub(crate) open: AtomicUsize,
+}
+
+impl IdleSlots {
+    pub(crate) fn new() -> IdleSlots {
+        IdleSlots {
+            epoch: Instant::now(),
+            slots: Mutex::new(Vec::new()),
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn add(&self, slot: Arc<Slot>) {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(slot);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    // Records a use of the slot, waiting while its connections are being
+    // closed, and returns whether all of them were closed since the previous
+    // use.
+    pub(crate) fn touch(&self, slot: &Slot) -> bool {
+        let now = self.now();
+        loop {
+            let last_used = slot.last_used.load(Ordering::Acquire);
+            if last_used == CLOSING {
+                thread::yield_now();
+            } else if slot
+                .last_used
+                .compare_exchange_weak(last_used, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return last_used == CLOSED;
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    // Closes the connections of the slots unused for at least `idle`, other
+    // than `keep`, and returns how many were closed. With `replaced`, the
+    // replaced connections of the other slots are closed too, which the
+    // caller must have made sure nothing borrows. A slot touched meanwhile
+    // is left alone, and so is a slot with statements.
+    fn close(&self, idle: Duration, retain: Retain, keep: Option<&Slot>, replaced: bool) -> usize {
+        let idle = idle.as_millis() as u64;
+        let mut closed = 0;
+        for slot in self
+            .slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            let now = self.now();
+            let last_used = slot.last_used.load(Ordering::Acquire);
+            let is_idle = last_used < CLOSED
+                && now.saturating_sub(last_used) >= idle
+                && !keep.is_some_and(|keep| std::ptr::eq(&**slot, keep))
+                && slot
+                    .last_used
+                    .compare_exchange(last_used, CLOSING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok();
+            if !is_idle {
+                if replaced {
+                    let mut connections = slot.connections();
+                    let count = connections.len().saturating_sub(1);
+                    connections.drain(..count);
+                    closed += count;
+                }
+                continue;
+            }
+
+            // No new statement can be prepared on the connections while the
+            // slot is CLOSING.
+            let mut connections = slot.connections();
+            if slot.statements.load(Ordering::Acquire) == 0 {
+                let is_last_open =
+                    self.open.load(Ordering::Acquire).saturating_sub(closed) <= connections.len();
+                let keep_last = match connections.last() {
+                    Some(conn) => {
+                        retain == Retain::Every
+                            || (retain == Retain::One && is_last_open)
+                            || !is_closable(conn)
+                    }
+                    None => false,
+                };
+                let count = connections.len() - keep_last as usize;
+                connections.drain(..count);
+                closed += count;
+            }
+            let state = if connections.is_empty() {
+                CLOSED
+            } else {
+                last_used
+            };
+            slot.last_used.store(state, Ordering::Release);
+        }
+
+        self.open.fetch_sub(closed, Ordering::AcqRel);
+        closed
+    }
+}
+
+// Whether closing the connection loses nothing: it is not in a transaction
+// and has no TEMP tables or other TEMP objects.
+fn is_closable(conn: &Connection) -> bool {
+    let temp_objects = conn.query_row("SELECT count(*) FROM temp.sqlite_master", [], |row| {
+        row.get::<_, i64>(0)
+    });
+    conn.is_autocommit() && temp_objects == Result::Ok(0)
+}
+
+impl SyncSqliteConnection {
+    fn retain(&self) -> Retain {
+        if !self.uri.contains("mode=memory") {
+            Retain::None
+        } else if self.registered {
+            Retain::One
+        } else {
+            Retain::Every
+        }
+    }
+
+    /// Starts a background thread that, every `interval`, closes the
+    /// connections of the threads that have not used the handle for at least
+    /// `interval`. A thread opens a new connection on its next use. The
+    /// connections of a thread with statements from `prepare` are kept, as
+    /// are connections in a transaction, with TEMP tables or other TEMP
+    /// objects, or of a `CacheMode::Private` handle, where each connection
+    /// has its own database. The last open connection to an in-memory
+    /// database is kept too, so that it isn't freed. Per-connection state not
+    /// set up through this handle, e.g. a PRAGMA run with `execute`, is
+    /// lost. Clones of the
+    /// handle get their own reaper; the thread stops when the handle is
+    /// dropped.
+    ///
+    /// `close_idle_connections` does the same on demand without any of the
+    /// requirements below.
+    ///
+    /// # Safety
+    ///
+    /// A thread counts as idle from its last call into the handle, e.g. a
+    /// method call or a dereference to the `Connection`. References handed
+    /// out by that call are not tracked: the caller must ensure that no
+    /// thread keeps using a reference to its connection, whether from
+    /// `Deref`, `force`, a transaction, `acquire` or `blob_open`, for
+    /// `interval` or longer, including a single statement that runs that
+    /// long. Otherwise the reaper closes the connection while it is in use.
+    /// This also applies to clones of the handle.
+    pub unsafe fn with_idle_reaper(mut self, interval: Duration) -> Self {
+        self.start_reaper(interval);
+        self
+    }
+
+    // Replaces the reaper of the handle, if any. See with_idle_reaper for
+    // the caller's obligations.
+    pub(crate) unsafe fn start_reaper(&mut self, interval: Duration) {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let slots = self.idle_slots.clone();
+        let retain = self.retain();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                slots.close(interval, retain, None, false);
+            }
+        });
+        self.reaper = Some((interval, stop));
+    }
+
+    /// Closes the connections of the threads that have not used the handle
+    /// for at least `idle`, as well as the connections replaced by
+    /// `reset_thread_connection` and `clear_connections`, and returns how many
+    /// were closed. A thread opens a new connection on its next use. Taking
+    /// `&mut self` guarantees that no statement or reference still uses them.
+    ///
+    /// The current thread's connection stays open, keeping the database
+    /// alive. Connections in a transaction, with TEMP tables or other TEMP
+    /// objects, or of a `CacheMode::Private` handle, where each connection
+    /// has its own database, are kept as well. Per-connection state not set
+    /// up through this handle, e.g. a PRAGMA run with `execute`, is lost.
+    pub fn close_idle_connections(&mut self, idle: Duration) -> Result<usize> {
+        self.try_get()?;
+        let retain = self.retain();
+        let current = self.local().slot.clone();
+        Result::Ok(self.idle_slots.close(idle, retain, Some(&current), true))
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_close_idle_connections() {
+        let idle = std::time::Duration::from_millis(20);
+        let mut c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let count = |c1: &crate::SyncSqliteConnection| -> i64 {
+            c1.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        // Keeps the threads alive together, so they don't reuse each other's
+        // connections.
+        let barrier = std::sync::Barrier::new(3);
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    assert_eq!(count(&c1), 1);
+                    barrier.wait();
+                });
+            }
+        });
+        c1.reset_thread_connection();
+        c1.health_check().unwrap();
+        assert_eq!(c1.open_connection_count(), 5);
+
+        // Only the replaced connection is closed before the threads are idle.
+        assert_eq!(c1.close_idle_connections(idle * 100).unwrap(), 1);
+        std::thread::sleep(idle);
+        assert_eq!(c1.close_idle_connections(idle).unwrap(), 3);
+        assert_eq!(c1.open_connection_count(), 1);
+
+        assert_eq!(count(&c1), 1);
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(count(&c1), 1));
+        });
+        assert_eq!(c1.open_connection_count(), 2);
+    }
+
+    #[test]
+    fn test_idle_reaper() {
+        let interval = std::time::Duration::from_millis(20);
+        // Threads only use the handle through its methods here.
+        let c1 = unsafe {
+            crate::SyncSqliteConnection::new()
+                .unwrap()
+                .with_idle_reaper(interval)
+        };
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let count = |c1: &crate::SyncSqliteConnection| -> i64 {
+            c1.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                .unwrap()
+        };
+        let insert = c1.prepare("INSERT INTO t VALUES (?)").unwrap();
+
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(count(&c1), 1);
+                barrier.wait();
+                barrier.wait();
+            });
+            s.spawn(|| {
+                assert_eq!(count(&c1), 1);
+                barrier.wait();
+                barrier.wait();
+            });
+            barrier.wait();
+            assert_eq!(c1.open_connection_count(), 3);
+
+            // The two threads are reaped, the main thread keeps its
+            // connection for the statement prepared on it.
+            let deadline = std::time::Instant::now() + interval * 100;
+            while c1.open_connection_count() > 1 && std::time::Instant::now() < deadline {
+                std::thread::sleep(interval);
+            }
+            assert_eq!(c1.open_connection_count(), 1);
+            barrier.wait();
+        });
+
+        // The statement still runs on the connection a transaction uses.
+        let tx = c1.transaction().unwrap();
+        insert.execute([2]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(count(&c1), 2);
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(count(&c1), 2));
+        });
+    }
+
+    #[test]
+    fn test_idle_reaper_keeps_database() {
+        let interval = std::time::Duration::from_millis(5);
+        let c1 = unsafe {
+            crate::SyncSqliteConnection::new()
+                .unwrap()
+                .with_idle_reaper(interval)
+        };
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| c1.health_check().unwrap());
+        });
+
+        // Every thread is idle, yet one connection stays open so that SQLite
+        // doesn't free the database.
+        std::thread::sleep(interval * 20);
+        assert_eq!(c1.open_connection_count(), 1);
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_close_idle_connections_keeps_state() {
+        let idle = std::time::Duration::from_millis(0);
+        let mut c1 = crate::SyncSqliteConnection::new().unwrap();
+        let mut private =
+            crate::SyncSqliteConnection::new_with_cache(crate::CacheMode::Private).unwrap();
+
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.execute_batch("CREATE TEMP TABLE scratch (x INTEGER)")
+                    .unwrap();
+                private.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+                barrier.wait();
+            });
+            s.spawn(|| {
+                c1.execute_batch("BEGIN").unwrap();
+                barrier.wait();
+            });
+        });
+        assert_eq!(c1.close_idle_connections(idle).unwrap(), 0);
+        assert_eq!(private.close_idle_connections(idle).unwrap(), 0);
+        assert_eq!(c1.open_connection_count(), 3);
+    }
+}
@@ -0,0 +1,294 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::*;
+use serde_json::{Map, Number, Value};
+
+use crate::{quote_identifier, SyncSqliteConnection, SyncStatement};
+
+// INTEGER and REAL become numbers (non-finite reals become null), TEXT a
+// string, BLOB a base64 string and NULL null.
+pub(crate) fn value_ref_to_json(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::Number(Number::from(i)),
+        ValueRef::Real(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+        ValueRef::Text(text) => Value::String(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(blob) => Value::String(STANDARD.encode(blob)),
+    }
+}
+
+// The key of the object export_json turns a BLOB into.
+const BLOB_TAG: &str = "$blob";
+
+// Like value_ref_to_json, with a BLOB as `{"$blob": base64}` so that it can't
+// be taken for TEXT when read back, whatever the column type.
+fn value_ref_to_tagged_json(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Blob(blob) => {
+            let mut object = Map::new();
+            object.insert(BLOB_TAG.to_owned(), Value::String(STANDARD.encode(blob)));
+            Value::Object(object)
+        }
+        value => value_ref_to_json(value),
+    }
+}
+
+fn decode_blob(s: &str) -> Result<SqlValue> {
+    STANDARD
+        .decode(s)
+        .map(SqlValue::Blob)
+        .map_err(|err| Error::ToSqlConversionFailure(Box::new(err)))
+}
+
+// The inverse of value_ref_to_tagged_json. Untagged strings are decoded as
+// base64 as well when the column is declared as a BLOB. Other arrays and
+// objects are stored as JSON text.
+fn json_to_value(value: &Value, blob: bool) -> Result<SqlValue> {
+    if let Value::Object(object) = value {
+        if let (1, Some(Value::String(s))) = (object.len(), object.get(BLOB_TAG)) {
+            return decode_blob(s);
+        }
+    }
+    Result::Ok(match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(i64::from(*b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) if blob => decode_blob(s)?,
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    })
+}
+
+fn invalid_json(message: String) -> Error {
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_MISUSE), Some(message))
+}
+
+pub(crate) fn row_to_json(
+    row: &Row<'_>,
+    column_names: &[String],
+    encode: fn(ValueRef<'_>) -> Value,
+) -> Result<Value> {
+    let mut object = Map::new();
+    for (idx, name) in column_names.iter().enumerate() {
+        object.insert(name.clone(), encode(row.get_ref(idx)?));
+    }
+    Result::Ok(Value::Object(object))
+}
+
+impl<'conn> SyncStatement<'conn> {
+    /// Runs the query and returns every row as a JSON object keyed by column
+    /// name. BLOBs are base64 encoded.
+    pub fn query_json<P>(&self, params: P) -> Result<Vec<Value>>
+    where
+        P: Params,
+    {
+        let column_names = self.column_names();
+        let mut rows = self.query(params)?;
+
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            result.push(row_to_json(row, &column_names, value_ref_to_json)?);
+        }
+        Result::Ok(result)
+    }
+}
+
+impl SyncSqliteConnection {
+    /// Returns an object mapping the name of every table to an array of its
+    /// rows, encoded like `SyncStatement::query_json` does except for BLOBs,
+    /// which become `{"$blob": base64}` objects so that `import_json` can
+    /// tell them from TEXT in any column.
+    pub fn export_json(&self) -> Result<Value> {
+        let conn = self.try_get()?;
+        let mut tables = Map::new();
+        for table in self.tables()? {
+            let mut stmt = conn.prepare(&format!("SELECT * FROM {}", quote_identifier(&table)))?;
+            let column_names: Vec<String> =
+                stmt.column_names().into_iter().map(String::from).collect();
+            let mut rows = stmt.query([])?;
+
+            let mut result = Vec::new();
+            while let Some(row) = rows.next()? {
+                result.push(row_to_json(row, &column_names, value_ref_to_tagged_json)?);
+            }
+            tables.insert(table, Value::Array(result));
+        }
+        Result::Ok(Value::Object(tables))
+    }
+
+    /// Inserts the rows of an object shaped like the output of `export_json`
+    /// into the existing tables, all in one transaction. Plain base64 strings
+    /// are accepted for BLOBs in columns declared as BLOB. Fails without
+    /// changes if a table does not exist or a row has a column the table
+    /// lacks.
+    pub fn import_json(&self, value: &Value) -> Result<()> {
+        let tables = value
+            .as_object()
+            .ok_or_else(|| invalid_json("expected an object of tables".to_owned()))?;
+
+        let tx = self.transaction()?;
+        for (table, rows) in tables {
+            let mut stmt = tx.prepare("SELECT name, upper(type) FROM pragma_table_info(?)")?;
+            let columns: Vec<(String, String)> = stmt
+                .query_map([table], |row| Result::Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_>>()?;
+            if columns.is_empty() {
+                return Result::Err(Error::SqliteFailure(
+                    ffi::Error::new(ffi::SQLITE_ERROR),
+                    Some(format!("no such table: {}", table)),
+                ));
+            }
+
+            let rows = rows
+                .as_array()
+                .ok_or_else(|| invalid_json(format!("expected an array of rows for {}", table)))?;
+            for row in rows {
+                let row = row
+                    .as_object()
+                    .ok_or_else(|| invalid_json(format!("expected row objects for {}", table)))?;
+                let mut names = Vec::new();
+                let mut values = Vec::new();
+                for (name, value) in row {
+                    let (_, decl_type) = columns
+                        .iter()
+                        .find(|(column, _)| column == name)
+                        .ok_or_else(|| Error::InvalidColumnName(name.clone()))?;
+                    names.push(quote_identifier(name));
+                    values.push(json_to_value(value, decl_type.contains("BLOB"))?);
+                }
+
+                let placeholders = vec!["?"; values.len()].join(", ");
+                let sql = if values.is_empty() {
+                    format!("INSERT INTO {} DEFAULT VALUES", quote_identifier(table))
+                } else {
+                    format!(
+                        "INSERT INTO {} ({}) VALUES ({})",
+                        quote_identifier(table),
+                        names.join(", "),
+                        placeholders
+                    )
+                };
+                tx.prepare_cached(&sql)?.execute(params_from_iter(values))?;
+            }
+        }
+        tx.commit().map(|_| ())
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_query_json() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (i INTEGER, r REAL, s TEXT, b BLOB, n TEXT);
+             INSERT INTO t VALUES (1, 1.5, 'text', x'00ff10', NULL);",
+        )
+        .unwrap();
+
+        let rows = c1
+            .prepare("SELECT i, r, s, b, n FROM t")
+            .unwrap()
+            .query_json(rusqlite::params![])
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![serde_json::json!({
+                "i": 1,
+                "r": 1.5,
+                "s": "text",
+                "b": "AP8Q",
+                "n": null,
+            })]
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE users (id INTEGER, name TEXT, avatar BLOB);
+             CREATE TABLE \"empty table\" (x INTEGER);
+             INSERT INTO users VALUES (1, 'a', x'0102'), (2, NULL, NULL);",
+        )
+        .unwrap();
+
+        assert_eq!(
+            c1.export_json().unwrap(),
+            serde_json::json!({
+                "empty table": [],
+                "users": [
+                    { "id": 1, "name": "a", "avatar": { "$blob": "AQI=" } },
+                    { "id": 2, "name": null, "avatar": null },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_import_json() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE users (id INTEGER, name TEXT, avatar BLOB, score REAL);
+             CREATE TABLE tags (tag TEXT);
+             INSERT INTO users VALUES (1, 'a', x'0102', 0.5), (2, NULL, NULL, NULL);
+             INSERT INTO tags VALUES ('x'), ('y');",
+        )
+        .unwrap();
+
+        let exported = c1.export_json().unwrap();
+        c1.execute_batch("DELETE FROM users; DELETE FROM tags;")
+            .unwrap();
+        c1.import_json(&exported).unwrap();
+        assert_eq!(c1.export_json().unwrap(), exported);
+        let avatar: Vec<u8> = c1
+            .query_row("SELECT avatar FROM users WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(avatar, [1, 2]);
+
+        let plain = serde_json::json!({ "users": [{ "id": 3, "avatar": "AwQ=" }] });
+        c1.import_json(&plain).unwrap();
+        let avatar: Vec<u8> = c1
+            .query_row("SELECT avatar FROM users WHERE id = 3", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(avatar, [3, 4]);
+        c1.execute("DELETE FROM users WHERE id = 3", []).unwrap();
+
+        let missing_table = serde_json::json!({ "tags": [{ "tag": "z" }], "missing": [] });
+        assert!(c1.import_json(&missing_table).is_err());
+        let unknown_column = serde_json::json!({ "tags": [{ "tag": "z", "color": "red" }] });
+        assert!(c1.import_json(&unknown_column).is_err());
+        assert_eq!(c1.export_json().unwrap(), exported);
+    }
+
+    #[test]
+    fn test_import_json_untyped() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (v);
+             INSERT INTO t VALUES (x'0102'), ('AQI='), (1), ('{\"$blob\": 1}');",
+        )
+        .unwrap();
+
+        let exported = c1.export_json().unwrap();
+        c1.execute_batch("DELETE FROM t").unwrap();
+        c1.import_json(&exported).unwrap();
+        assert_eq!(c1.export_json().unwrap(), exported);
+        let types: Vec<String> = c1
+            .prepare("SELECT typeof(v) FROM t ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(types, ["blob", "text", "integer", "text"]);
+    }
+}
@@ -0,0 +1,80 @@
+use rusqlite::*;
+
+use crate::{quote_identifier, SyncSqliteConnection, SyncStatement};
+
+/// A key-value store of byte strings kept in a table of the database. Stores
+/// over the same table, in any thread or through any handle of the database,
+/// see each other's writes.
+pub struct KvStore<'conn> {
+    set: SyncStatement<'conn>,
+    get: SyncStatement<'conn>,
+    delete: SyncStatement<'conn>,
+    keys: SyncStatement<'conn>,
+}
+
+impl<'conn> KvStore<'conn> {
+    /// Uses `table` as the store, creating it if it does not exist yet.
+    pub fn new(conn: &'conn SyncSqliteConnection, table: &str) -> Result<KvStore<'conn>> {
+        let table = quote_identifier(table);
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value BLOB NOT NULL) \
+             WITHOUT ROWID",
+            table
+        ))?;
+
+        Result::Ok(KvStore {
+            set: conn.prepare(&format!(
+                "INSERT INTO {} (key, value) VALUES (?, ?) \
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                table
+            ))?,
+            get: conn.prepare(&format!("SELECT value FROM {} WHERE key = ?", table))?,
+            delete: conn.prepare(&format!("DELETE FROM {} WHERE key = ?", table))?,
+            keys: conn.prepare(&format!("SELECT key FROM {} ORDER BY key", table))?,
+        })
+    }
+
+    /// Stores `value` under `key`, replacing any previous value.
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.set.execute(params![key, value]).map(|_| ())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get.query_scalar_optional([key])
+    }
+
+    /// Removes `key` and returns whether it was present.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        self.delete.execute([key]).map(|changed| changed > 0)
+    }
+
+    /// The keys in the store, in ascending order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.keys.query_collect([], |row| row.get(0))
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_kv_store() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let kv = crate::KvStore::new(&c1, "kv").unwrap();
+        kv.set("a", b"1").unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let other = crate::KvStore::new(&c1, "kv").unwrap();
+                assert_eq!(other.get("a").unwrap(), Some(b"1".to_vec()));
+                other.set("a", b"2").unwrap();
+                other.set("b", b"3").unwrap();
+                assert!(kv.delete("b").unwrap());
+                assert!(!kv.delete("b").unwrap());
+            });
+        });
+
+        assert_eq!(kv.get("a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(kv.get("b").unwrap(), None);
+        assert_eq!(kv.keys().unwrap(), vec!["a"]);
+    }
+}
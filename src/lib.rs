@@ -1,22 +1,121 @@
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex, OnceLock, PoisonError, RwLock};
 
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashMap;
 use std::ops::Deref;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use std::convert;
 use std::result;
 
+use rusqlite::types::{Value, ValueRef};
 use rusqlite::*;
 
 use thread_local::ThreadLocal;
 
+use idle::{IdleSlots, Slot, StatementCount};
+
+mod builder;
+mod config;
+#[cfg(feature = "csv")]
+mod csv;
+mod error;
+#[cfg(feature = "load_extension")]
+mod extension;
+mod functions;
+mod guard;
+mod idle;
+#[cfg(feature = "json")]
+mod json;
+mod kv;
+mod memory;
+mod metrics;
+mod row;
+mod schema;
+mod snapshot;
+mod transaction;
+mod workspace;
+
+pub use builder::SyncSqliteConnectionBuilder;
+pub use config::{JournalMode, Synchronous};
+pub use error::{extended_code, is_busy, is_constraint_violation, InMemoryError};
+pub use guard::ConnGuard;
+pub use kv::KvStore;
+pub use memory::set_soft_heap_limit;
+pub use metrics::MetricsSink;
+pub use row::{RowExt, RowSnapshot};
+pub use rusqlite::types::{FromSql, ToSql};
+pub use rusqlite::Row;
+pub use rusqlite::{named_params, params};
+pub use snapshot::Snapshot;
+pub use transaction::{SyncSavepoint, SyncTransaction};
+pub use workspace::SyncSqliteWorkspace;
+
+/// Builds the `&[(&str, &dyn ToSql)]` slice taken by the `_named` methods,
+/// e.g. `bind![":id" => 1, ":name" => name]`.
+#[macro_export]
+macro_rules! bind {
+    () => {
+        &[] as &[(&str, &dyn $crate::ToSql)]
+    };
+    ($($name:expr => $value:expr),+ $(,)?) => {
+        &[$(($name, &$value as &dyn $crate::ToSql)),+] as &[(&str, &dyn $crate::ToSql)]
+    };
+}
+
 static COUNTER: AtomicU64 = AtomicU64::new(0u64);
 
+/// Restarts the names given by `SyncSqliteConnection::new` from `shared_0`,
+/// e.g. for tests asserting on them. Databases still open keep their names,
+/// so a later `new` may open one of them instead of an empty database: only
+/// call this while no other thread uses the crate, such as at the start of a
+/// process that has not opened any database yet.
+pub fn reset_counter() {
+    COUNTER.store(0u64, Ordering::Release);
+}
+
+fn shared_uri(name: &str) -> String {
+    format!("file:{}?mode=memory&cache=shared", name)
+}
+
+fn named_uri(name: &str, shared: bool) -> String {
+    if shared {
+        shared_uri(name)
+    } else {
+        format!("file:{}?mode=memory", name)
+    }
+}
+
 pub fn open_shared(name: &str) -> Result<Connection> {
-    let uri = format!("file:{}?mode=memory&cache=shared", name);
-    Connection::open(uri)
+    Connection::open(shared_uri(name))
+}
+
+/// Opens the named in-memory database, through the shared cache like
+/// `open_shared` if `shared` is set. Without it, the name does not refer to
+/// a common database: every connection gets its own, empty one.
+pub fn open_named(name: &str, shared: bool) -> Result<Connection> {
+    Connection::open(named_uri(name, shared))
+}
+
+/// Like `open_shared` with the given flags. The URI and shared cache flags are
+/// always set, since the database is only shared through the shared cache.
+pub fn open_shared_with_flags(name: &str, flags: OpenFlags) -> Result<Connection> {
+    Connection::open_with_flags(shared_uri(name), shared_flags(flags))
 }
 
+fn shared_flags(flags: OpenFlags) -> OpenFlags {
+    (flags - OpenFlags::SQLITE_OPEN_PRIVATE_CACHE)
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+}
+
+/// Opens a new database named from a process-wide counter. Shared-cache
+/// memory databases only exist within a process, so the same name in
+/// another process refers to a different database.
 pub fn new_shared() -> Result<Connection> {
     open_shared(&format!(
         "shared_{}",
@@ -24,40 +123,559 @@ pub fn new_shared() -> Result<Connection> {
     ))
 }
 
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Returns where `sql` ends without trailing semicolons, whitespace and
+// comments, and whether it has a LIMIT outside of parentheses, skipping string
+// literals, quoted identifiers and comments.
+fn scan_page_sql(sql: &str) -> (usize, bool) {
+    let bytes = sql.as_bytes();
+    let find = |from: usize, pattern: &str| {
+        sql[from..]
+            .find(pattern)
+            .map_or(bytes.len(), |i| from + i + pattern.len())
+    };
+    let (mut i, mut end, mut depth, mut has_limit) = (0, 0, 0, false);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => {
+                i = find(i + 1, &sql[i..i + 1]);
+                end = i;
+            }
+            b'[' => {
+                i = find(i + 1, "]");
+                end = i;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = find(i, "\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = find(i + 2, "*/"),
+            b';' => i += 1,
+            byte if byte.is_ascii_whitespace() => i += 1,
+            byte if byte.is_ascii_alphanumeric()
+                || byte == b'_'
+                || byte == b'$'
+                || byte >= 0x80 =>
+            {
+                let start = i;
+                while i < bytes.len()
+                    && (bytes[i].is_ascii_alphanumeric()
+                        || bytes[i] == b'_'
+                        || bytes[i] == b'$'
+                        || bytes[i] >= 0x80)
+                {
+                    i += 1;
+                }
+                has_limit |= depth == 0 && sql[start..i].eq_ignore_ascii_case("limit");
+                end = i;
+            }
+            byte => {
+                match byte {
+                    b'(' => depth += 1,
+                    b')' => depth -= 1,
+                    _ => {}
+                }
+                i += 1;
+                end = i;
+            }
+        }
+    }
+    (end, has_limit)
+}
+
+// A thread's connections are kept open until the SyncSqliteConnection is
+// dropped or closes them, through `&mut self` or its idle reaper, since
+// statements or references handed out earlier may still borrow them.
+#[derive(Default)]
+pub(crate) struct LocalConnection {
+    slot: Arc<Slot>,
+    // The SyncSqliteConnection generation the last connection was opened
+    // for, or None if it has to be replaced.
+    generation: Cell<Option<u64>>,
+    // How many of the registered initializers ran on the last connection.
+    initialized: Cell<usize>,
+}
+
+// The number of SyncSqliteConnection handles of each shared in-memory
+// database. SQLite frees such a database when its last connection closes, so
+// handles close their connections while holding the lock, and a database
+// without handles here is gone.
+fn live_handles() -> &'static Mutex<HashMap<String, usize>> {
+    static LIVE_HANDLES: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+    LIVE_HANDLES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Whether the seed with the given key ran on a database, by database name
+// and key. The entries of a shared in-memory database go away with it.
+type Seeds = HashMap<(String, String), Arc<Mutex<bool>>>;
+
+fn seeds() -> &'static Mutex<Seeds> {
+    static SEEDS: OnceLock<Mutex<Seeds>> = OnceLock::new();
+    SEEDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Per-connection setup (functions, pragmas, ...) that every thread's
+// connection needs. It runs on each connection before it is first used.
+pub(crate) type Initializer = Arc<dyn Fn(&Connection) -> Result<()> + Send + Sync>;
+
+// 64-bit FNV-1a, used where hashes have to be stable across runs and builds.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> StableHasher {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Whether the connections of a `SyncSqliteConnection` share one database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every thread's connection uses the same database.
+    Shared,
+    /// Every thread's connection has its own database, so a thread only sees
+    /// its own writes.
+    Private,
+}
+
 pub struct SyncSqliteConnection {
-    connection: ThreadLocal<Connection>,
+    connection: ThreadLocal<LocalConnection>,
+    // Number of entries in `connection`. ThreadLocal::iter needs a Sync
+    // value, which LocalConnection is not.
+    connection_count: AtomicUsize,
+    generation: AtomicU64,
+    initializers: RwLock<Vec<Initializer>>,
+    initializer_count: AtomicUsize,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    // The connections in `connection`, reachable from the idle reaper.
+    idle_slots: Arc<IdleSlots>,
+    // The interval of the idle reaper and the channel whose closing stops it.
+    reaper: Option<(Duration, mpsc::Sender<()>)>,
+    flags: OpenFlags,
     name: String,
+    // Whether the handle is counted in live_handles.
+    registered: bool,
+    uri: String,
 }
 
 impl SyncSqliteConnection {
+    /// Opens a new database named `shared_{n}` from a process-wide counter.
+    /// Like every shared-cache memory database it is only visible within this
+    /// process. Names given to `open` can collide with these; `new_unique`
+    /// avoids that.
     pub fn new() -> Result<Self> {
-        let name = format!("shared_{}", COUNTER.fetch_add(1u64, Ordering::AcqRel));
+        let n = COUNTER.fetch_add(1u64, Ordering::AcqRel);
+        debug_assert!(n != u64::MAX, "database name counter wrapped around");
+        SyncSqliteConnection::open(format!("shared_{}", n))
+    }
 
-        let this = SyncSqliteConnection {
-            connection: ThreadLocal::new(),
-            name: name,
-        };
+    /// Opens a new database with a random UUID name, which won't collide with
+    /// any name chosen elsewhere.
+    pub fn new_unique() -> Result<Self> {
+        let name = format!("unique_{}", uuid::Uuid::new_v4().simple());
+        SyncSqliteConnection::open(name)
+    }
 
-        this.try_get()?;
+    /// Like `new`, with `CacheMode::Private` giving each thread a separate
+    /// database instead of sharing one.
+    pub fn new_with_cache(mode: CacheMode) -> Result<Self> {
+        let name = format!("shared_{}", COUNTER.fetch_add(1u64, Ordering::AcqRel));
+        SyncSqliteConnection::open_named(name, mode == CacheMode::Shared)
+    }
+
+    /// Like `new`, running `schema` (e.g. an embedded `CREATE TABLE` script)
+    /// on the new database. Fails, freeing the database, if any statement of
+    /// the schema does.
+    pub fn new_with_schema(schema: &str) -> Result<Self> {
+        let this = SyncSqliteConnection::new()?;
+        this.try_get()?.execute_batch(schema)?;
         Result::Ok(this)
     }
 
     pub fn open(name: String) -> Result<Self> {
+        let uri = shared_uri(&name);
+        SyncSqliteConnection::open_uri(name, uri, OpenFlags::default())
+    }
+
+    /// Like `open`, but fails with `InMemoryError::DatabaseEvicted` instead of
+    /// creating an empty database if no handle keeps the database alive.
+    /// Connections opened with `open_shared` are not taken into account.
+    pub fn open_existing(name: String) -> result::Result<Self, InMemoryError> {
+        let mut handles = live_handles()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !handles.contains_key(&name) {
+            return Result::Err(InMemoryError::DatabaseEvicted(name));
+        }
+
+        let uri = shared_uri(&name);
+        let mut this = SyncSqliteConnection::connect(name, uri, OpenFlags::default())?;
+        this.register(&mut handles);
+        Result::Ok(this)
+    }
+
+    /// Like `open`, but if `shared` is not set the database is not shared
+    /// between threads: each thread's connection opens a separate, empty
+    /// database and sees only its own writes.
+    pub fn open_named(name: String, shared: bool) -> Result<Self> {
+        let uri = named_uri(&name, shared);
+        SyncSqliteConnection::open_uri(name, uri, OpenFlags::default())
+    }
+
+    /// Like `open`, opening the connection of every thread with the given
+    /// flags. As with `open_shared_with_flags`, the shared cache is forced.
+    pub fn open_with_flags(name: String, flags: OpenFlags) -> Result<Self> {
+        let uri = shared_uri(&name);
+        SyncSqliteConnection::open_uri(name, uri, shared_flags(flags))
+    }
+
+    /// Opens a database file with a shared cache instead of an in-memory
+    /// database, so that other processes can open the same file. Unlike the
+    /// in-memory databases, the data is written to disk, outlives every
+    /// handle and pays the cost of journaling and syncing each transaction.
+    /// The shared cache only spans the connections of this process; other
+    /// processes coordinate through SQLite's file locks.
+    pub fn new_file_shared<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let name = path.as_ref().to_string_lossy().into_owned();
+        let escaped = name
+            .replace('%', "%25")
+            .replace('?', "%3f")
+            .replace('#', "%23");
+        let uri = format!("file:{}?cache=shared", escaped);
+        SyncSqliteConnection::open_uri(name, uri, OpenFlags::default())
+    }
+
+    fn open_uri(name: String, uri: String, flags: OpenFlags) -> Result<Self> {
+        let shared_memory = uri == shared_uri(&name);
+        let mut this = SyncSqliteConnection::connect(name, uri, flags)?;
+        if shared_memory {
+            this.register(
+                &mut live_handles()
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+        Result::Ok(this)
+    }
+
+    fn connect(name: String, uri: String, flags: OpenFlags) -> Result<Self> {
         let this = SyncSqliteConnection {
             connection: ThreadLocal::new(),
+            connection_count: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            initializers: RwLock::new(Vec::new()),
+            initializer_count: AtomicUsize::new(0),
+            metrics: None,
+            idle_slots: Arc::new(IdleSlots::new()),
+            reaper: None,
+            flags,
             name: name,
+            registered: false,
+            uri,
         };
 
         this.try_get()?;
         Result::Ok(this)
     }
 
+    /// Copies the database into a new uniquely named one and returns a handle
+    /// to it. The fork inherits registered functions and settings but shares
+    /// no data with the original.
+    pub fn fork(&self) -> Result<SyncSqliteConnection> {
+        self.fork_without(&[])
+    }
+
+    // Like `fork`, leaving out the given initializers.
+    pub(crate) fn fork_without(&self, skipped: &[Initializer]) -> Result<SyncSqliteConnection> {
+        let mut fork = SyncSqliteConnection::new_unique()?;
+        fork.copy_initializers(self);
+        let initializers = fork
+            .initializers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        initializers.retain(|initializer| {
+            !skipped
+                .iter()
+                .any(|skipped| Arc::ptr_eq(initializer, skipped))
+        });
+        *fork.initializer_count.get_mut() = initializers.len();
+        fork.metrics = self.metrics.clone();
+
+        let mut target = open_shared(fork.name())?;
+        backup::Backup::new(self.try_get()?, &mut target)?.run_to_completion(
+            1024,
+            Duration::from_millis(0),
+            None,
+        )?;
+
+        Result::Ok(fork)
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
 
+    /// The URI every thread's connection is opened with, e.g. to `ATTACH` the
+    /// same database to another connection.
+    pub fn uri(&self) -> String {
+        self.uri.clone()
+    }
+
+    // Whether `conn` is one of the current thread's connections.
+    fn owns(&self, conn: &Connection) -> bool {
+        self.local()
+            .slot
+            .connections()
+            .iter()
+            .any(|owned| std::ptr::eq(&**owned, conn))
+    }
+
+    fn local(&self) -> &LocalConnection {
+        self.connection.get_or(|| {
+            self.connection_count.fetch_add(1, Ordering::AcqRel);
+            let local = LocalConnection::default();
+            self.idle_slots.add(local.slot.clone());
+            local
+        })
+    }
+
     fn try_get(&self) -> Result<&Connection> {
-        self.connection.get_or_try(|| open_shared(&self.name()))
+        let local = self.local();
+        if self.idle_slots.touch(&local.slot) {
+            local.generation.set(None);
+        }
+        let generation = self.generation.load(Ordering::Acquire);
+        let mut connections = local.slot.connections();
+        if local.generation.get() != Some(generation) {
+            connections.push(Box::new(Connection::open_with_flags(
+                &self.uri, self.flags,
+            )?));
+            self.idle_slots.open.fetch_add(1, Ordering::AcqRel);
+            local.generation.set(Some(generation));
+            local.initialized.set(0);
+        }
+
+        let conn: *const Connection = &**connections.last().unwrap();
+        drop(connections);
+        // The boxed connections are only dropped through `&mut self` or `self`,
+        // so they outlive the borrow of `self`, or by the idle reaper, whose
+        // caller vouched that the borrow ends before.
+        let conn = unsafe { &*conn };
+
+        if local.initialized.get() != self.initializer_count.load(Ordering::Acquire) {
+            let pending = self
+                .initializers
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)[local.initialized.get()..]
+                .to_vec();
+            for initializer in pending {
+                initializer(conn)?;
+                local.initialized.set(local.initialized.get() + 1);
+            }
+        }
+
+        Result::Ok(conn)
+    }
+
+    // Runs the initializer on the current thread's connection, so errors
+    // surface right away, then registers it for the connections of all other
+    // threads. Both happen under the lock, after catching up on the
+    // initializers other threads registered meanwhile, so that the current
+    // connection doesn't run the new one again as pending.
+    pub(crate) fn add_initializer(&self, initializer: Initializer) -> Result<()> {
+        let conn = self.try_get()?;
+        let local = self.local();
+        let mut initializers = self
+            .initializers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        for pending in initializers[local.initialized.get()..].to_vec() {
+            pending(conn)?;
+            local.initialized.set(local.initialized.get() + 1);
+        }
+        initializer(conn)?;
+
+        local.initialized.set(initializers.len() + 1);
+        initializers.push(initializer);
+        self.initializer_count
+            .store(initializers.len(), Ordering::Release);
+        Result::Ok(())
+    }
+
+    fn copy_initializers(&mut self, source: &SyncSqliteConnection) {
+        let initializers = source
+            .initializers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        self.initializer_count
+            .store(initializers.len(), Ordering::Release);
+        *self
+            .initializers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = initializers;
+    }
+
+    /// Runs `f` on the current thread's connection unless a seed with the same
+    /// key already ran on the database, through this or any other handle.
+    /// Unlike the per-connection setup done by `for_each_connection`, this
+    /// suits statements that change the shared data, e.g. inserting reference
+    /// rows. If `f` fails, the next call with the key runs again.
+    pub fn seed<F>(&self, key: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&Connection) -> Result<()>,
+    {
+        let seeded = seeds()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((self.name.clone(), key.to_owned()))
+            .or_default()
+            .clone();
+        let mut seeded = seeded.lock().unwrap_or_else(PoisonError::into_inner);
+        if !*seeded {
+            f(self.try_get()?)?;
+            *seeded = true;
+        }
+        Result::Ok(())
+    }
+
+    /// Hashes the serialized image of the main database. Databases built by the
+    /// same sequence of statements hash equally; equal data with a different
+    /// page layout may not.
+    pub fn content_hash(&self) -> Result<u64> {
+        let data = self.try_get()?.serialize("main")?;
+
+        let mut hasher = StableHasher::new();
+        hasher.write(&data);
+        Result::Ok(hasher.finish())
+    }
+
+    /// Hashes the rows of a table in rowid order, or primary key order for a
+    /// `WITHOUT ROWID` table. Unlike `content_hash`, only the values count:
+    /// tables with the same rows in the same order hash equally, whatever
+    /// their page layout, so this shows which tables of two databases differ.
+    pub fn table_hash(&self, table: &str) -> Result<u64> {
+        let conn = self.try_get()?;
+        let table = quote_identifier(table);
+        let mut stmt = match conn.prepare(&format!("SELECT * FROM {} ORDER BY rowid", table)) {
+            Result::Ok(stmt) => stmt,
+            // WITHOUT ROWID tables have no rowid and are scanned in primary
+            // key order.
+            Result::Err(_) => conn.prepare(&format!("SELECT * FROM {}", table))?,
+        };
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query([])?;
+
+        let mut hasher = StableHasher::new();
+        while let Some(row) = rows.next()? {
+            for idx in 0..column_count {
+                // A type tag and, for TEXT and BLOB, the length keep values of
+                // different types or boundaries from hashing the same bytes.
+                match row.get_ref(idx)? {
+                    ValueRef::Null => hasher.write(&[0]),
+                    ValueRef::Integer(i) => {
+                        hasher.write(&[1]);
+                        hasher.write(&i.to_le_bytes());
+                    }
+                    ValueRef::Real(f) => {
+                        hasher.write(&[2]);
+                        hasher.write(&f.to_bits().to_le_bytes());
+                    }
+                    ValueRef::Text(text) => {
+                        hasher.write(&[3]);
+                        hasher.write(&(text.len() as u64).to_le_bytes());
+                        hasher.write(text);
+                    }
+                    ValueRef::Blob(blob) => {
+                        hasher.write(&[4]);
+                        hasher.write(&(blob.len() as u64).to_le_bytes());
+                        hasher.write(blob);
+                    }
+                }
+            }
+            hasher.write(&[5]);
+        }
+        Result::Ok(hasher.finish())
+    }
+
+    /// Opens a BLOB in the given database (e.g. `MAIN_DB` or an attached
+    /// schema) for incremental I/O. The handle belongs to the current thread's
+    /// connection and cannot leave the thread.
+    pub fn blob_open<D: Name>(
+        &self,
+        db: D,
+        table: &str,
+        column: &str,
+        rowid: i64,
+        read_only: bool,
+    ) -> Result<blob::Blob<'_>> {
+        self.try_get()?
+            .blob_open(db, table, column, rowid, read_only)
+    }
+
+    /// Returns a handle that can be sent to another thread to abort the
+    /// statement running on the current thread's connection. It targets that
+    /// connection only: queries on other threads are not interrupted, and
+    /// neither is the connection that replaces it after a reset.
+    pub fn get_interrupt_handle(&self) -> Result<InterruptHandle> {
+        Result::Ok(self.try_get()?.get_interrupt_handle())
+    }
+
+    /// Runs a trivial query on the current thread's connection, opening it if
+    /// needed, to detect a connection that cannot be used.
+    pub fn health_check(&self) -> Result<()> {
+        self.try_get()?
+            .query_row("SELECT 1", [], |_| Result::Ok(()))
+    }
+
+    /// Runs `PRAGMA integrity_check` and returns the problems it reports, or
+    /// the single line `"ok"` if the database is intact.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.try_get()?.prepare("PRAGMA integrity_check")?;
+        let lines = stmt.query_map([], |row| row.get(0))?;
+        lines.collect()
+    }
+
+    /// Makes the current thread open a new connection on its next use. The
+    /// replaced connection stays open until this handle is dropped or
+    /// `close_idle_connections` is called, and statements already prepared on
+    /// this thread keep using it.
+    pub fn reset_thread_connection(&self) {
+        if let Some(local) = self.connection.get() {
+            local.generation.set(None);
+        }
+    }
+
+    /// Makes every thread open a new connection on its next use, like
+    /// `reset_thread_connection` does for the current thread.
+    pub fn clear_connections(&self) {
+        self.generation.fetch_add(1u64, Ordering::AcqRel);
+    }
+
+    /// Returns how many threads have a connection to the database through
+    /// this handle. The entry of a thread that exited is not closed but
+    /// handed to the next new thread, so this does not shrink.
+    pub fn connection_count(&self) -> usize {
+        self.connection_count.load(Ordering::Acquire)
+    }
+
+    /// Returns how many SQLite connections this handle holds open. Besides the
+    /// connection of each thread, this counts the connections replaced by
+    /// `reset_thread_connection` and `clear_connections`, which stay open
+    /// until the handle is dropped or `close_idle_connections` or the idle
+    /// reaper closes them.
+    pub fn open_connection_count(&self) -> usize {
+        self.idle_slots.open.load(Ordering::Acquire)
     }
 
     pub fn force(&self) -> &Connection {
@@ -68,6 +686,205 @@ impl SyncSqliteConnection {
     pub fn prepare(&self, sql: &str) -> Result<SyncStatement<'_>> {
         SyncStatement::new(self, sql.to_owned())
     }
+
+    /// Runs `base_sql` with `LIMIT ? OFFSET ?` appended and maps the rows, so
+    /// the page follows the order given by its ORDER BY. A trailing semicolon
+    /// is dropped. Fails with `SQLITE_MISUSE` if `base_sql` has a LIMIT clause
+    /// of its own; one in a subquery is fine.
+    pub fn query_page<T, F>(&self, base_sql: &str, limit: u32, offset: u32, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        let (end, has_limit) = scan_page_sql(base_sql);
+        if has_limit {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some("query_page: the query already has a LIMIT clause".to_owned()),
+            ));
+        }
+        // On a new line, so a trailing `--` comment doesn't swallow it.
+        let sql = format!("{}\nLIMIT ? OFFSET ?", &base_sql[..end]);
+        let mut stmt = self.try_get()?.prepare(&sql)?;
+        let rows = stmt.query_map([limit, offset], f)?;
+        rows.collect()
+    }
+
+    /// Counts the rows of `table`, or those matching `where_clause` if given,
+    /// e.g. `count("users", Some("age > ?"), [18])`. The table name is quoted
+    /// as an identifier, so it cannot inject SQL; values for the WHERE clause
+    /// should be passed as `params` rather than spliced into it.
+    pub fn count<P>(&self, table: &str, where_clause: Option<&str>, params: P) -> Result<i64>
+    where
+        P: Params,
+    {
+        let mut sql = format!("SELECT count(*) FROM {}", quote_identifier(table));
+        if let Some(where_clause) = where_clause {
+            sql = format!("{} WHERE {}", sql, where_clause);
+        }
+        self.try_get()?
+            .prepare_cached(&sql)?
+            .query_row(params, |row| row.get(0))
+    }
+
+    /// Runs the statements like `execute_batch` and returns how many rows each
+    /// of them changed, 0 for statements other than INSERT, UPDATE and DELETE.
+    /// The statements are split by SQLite itself, so semicolons in literals
+    /// and comments are handled.
+    pub fn execute_batch_counted(&self, sql: &str) -> Result<Vec<usize>> {
+        use rusqlite::fallible_iterator::FallibleIterator;
+
+        let conn = self.try_get()?;
+        let mut batch = Batch::new(conn, sql);
+        let mut counts = Vec::new();
+        while let Some(mut stmt) = batch.next()? {
+            let total = conn.total_changes();
+            let mut rows = stmt.raw_query();
+            while rows.next()?.is_some() {}
+            // changes() keeps the count of the last INSERT, UPDATE or DELETE,
+            // so only use it if this statement changed anything.
+            let changed = if conn.total_changes() != total {
+                conn.changes() as usize
+            } else {
+                0
+            };
+            counts.push(changed);
+        }
+        Result::Ok(counts)
+    }
+
+    /// Prepares the statement through the statement cache of the current
+    /// thread's connection. Unlike a `SyncStatement`, the result cannot be
+    /// shared with other threads.
+    pub fn prepare_cached(&self, sql: &str) -> Result<CachedStatement<'_>> {
+        self.try_get()?.prepare_cached(sql)
+    }
+
+    /// Runs `f` with the cached statement for `sql` of the current thread's
+    /// connection, which goes back to the cache afterwards.
+    pub fn with_cached<R, F>(&self, sql: &str, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut CachedStatement<'_>) -> Result<R>,
+    {
+        f(&mut self.prepare_cached(sql)?)
+    }
+
+    /// Prepares every statement, or none of them if any SQL fails to prepare.
+    pub fn prepare_many(&self, sqls: &[&str]) -> Result<Vec<SyncStatement<'_>>> {
+        sqls.iter().map(|sql| self.prepare(sql)).collect()
+    }
+
+    /// Inserts `row` into `table`, or updates the other columns of the row
+    /// that already has the same `key_cols`, and returns the rowid of the
+    /// inserted or updated row. `key_cols` must match a primary key or
+    /// unique index. Fails with `SQLITE_MISUSE` if either list is empty or the
+    /// table is a WITHOUT ROWID table, which has no rowid to return. Needs
+    /// SQLite 3.37.0 or later.
+    pub fn upsert(
+        &self,
+        table: &str,
+        key_cols: &[&str],
+        row: &[(&str, &dyn ToSql)],
+    ) -> Result<i64> {
+        if key_cols.is_empty() || row.is_empty() {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some("upsert needs at least one key column and one value".to_owned()),
+            ));
+        }
+        let conn = self.try_get()?;
+        let without_rowid: bool = conn
+            .prepare_cached("SELECT count(*) > 0 FROM pragma_table_list(?) WHERE wr")?
+            .query_row([table], |row| row.get(0))?;
+        if without_rowid {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some(format!(
+                    "upsert does not support WITHOUT ROWID table {}",
+                    table
+                )),
+            ));
+        }
+
+        let columns: Vec<String> = row.iter().map(|(name, _)| quote_identifier(name)).collect();
+        let mut updates: Vec<String> = row
+            .iter()
+            .filter(|(name, _)| !key_cols.contains(name))
+            .map(|(name, _)| quote_identifier(name))
+            .collect();
+        // A no-op update rather than DO NOTHING, so RETURNING still yields
+        // the existing row.
+        if updates.is_empty() {
+            updates.push(quote_identifier(key_cols[0]));
+        }
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} RETURNING rowid",
+            quote_identifier(table),
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", "),
+            key_cols
+                .iter()
+                .map(|name| quote_identifier(name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            updates
+                .iter()
+                .map(|name| format!("{} = excluded.{}", name, name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let values: Vec<&dyn ToSql> = row.iter().map(|(_, value)| *value).collect();
+        conn.prepare_cached(&sql)?
+            .query_row(values.as_slice(), |row| row.get(0))
+    }
+
+    /// Closes the connection of every thread. Once no other handle (e.g. a
+    /// clone) refers to the same name, SQLite frees the in-memory database.
+    /// All connections are closed even if one fails; the first error is returned.
+    pub fn shutdown(mut self) -> Result<()> {
+        let mut handles = live_handles()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut result = Result::Ok(());
+        let connections = std::mem::take(&mut self.connection)
+            .into_iter()
+            .flat_map(|local| std::mem::take(&mut *local.slot.connections()));
+        for conn in connections {
+            if let Err((_, err)) = conn.close() {
+                if result.is_ok() {
+                    result = Result::Err(err);
+                }
+            }
+        }
+
+        self.unregister(&mut handles);
+        result
+    }
+
+    fn register(&mut self, handles: &mut HashMap<String, usize>) {
+        *handles.entry(self.name.clone()).or_insert(0) += 1;
+        self.registered = true;
+    }
+
+    // Closes the connections and forgets the handle while the lock is held.
+    fn unregister(&mut self, handles: &mut HashMap<String, usize>) {
+        self.connection.clear();
+        self.idle_slots.clear();
+        if self.registered {
+            self.registered = false;
+            if let Some(count) = handles.get_mut(&self.name) {
+                *count -= 1;
+                if *count == 0 {
+                    handles.remove(&self.name);
+                    seeds()
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .retain(|(name, _), _| *name != self.name);
+                }
+            }
+        }
+    }
 }
 
 impl Deref for SyncSqliteConnection {
@@ -79,20 +896,99 @@ impl Deref for SyncSqliteConnection {
 
 impl Clone for SyncSqliteConnection {
     fn clone(&self) -> Self {
-        SyncSqliteConnection::open(self.name().clone())
-            .expect("ERROR: opening the sqlite database has failed!")
+        let mut this =
+            SyncSqliteConnection::open_uri(self.name().clone(), self.uri.clone(), self.flags)
+                .expect("ERROR: opening the sqlite database has failed!");
+        this.copy_initializers(self);
+        this.metrics = self.metrics.clone();
+        if let Some((interval, _)) = self.reaper {
+            // with_idle_reaper's requirements extend to clones.
+            unsafe { this.start_reaper(interval) };
+        }
+        this
     }
 
     fn clone_from(&mut self, source: &Self) {
+        let mut handles = live_handles()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.unregister(&mut handles);
         self.name = source.name().clone();
-        self.connection.clear();
+        self.uri = source.uri.clone();
+        self.flags = source.flags;
+        if source.registered {
+            self.register(&mut handles);
+        }
+        *self.connection_count.get_mut() = 0;
+        self.idle_slots = Arc::new(IdleSlots::new());
+        self.copy_initializers(source);
+        self.metrics = source.metrics.clone();
+        self.reaper = None;
+        if let Some((interval, _)) = source.reaper {
+            // with_idle_reaper's requirements extend to clones.
+            unsafe { self.start_reaper(interval) };
+        }
+    }
+}
+
+impl Drop for SyncSqliteConnection {
+    fn drop(&mut self) {
+        if self.registered {
+            self.unregister(
+                &mut live_handles()
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+        // The reaper may still hold the slots until it notices the handle is
+        // gone.
+        self.idle_slots.clear();
     }
 }
 
-struct SendStatement<'a>(Statement<'a>);
+// The statement and the connection it was prepared on, which stays the same
+// when the thread's connection is reset. A Statement is not Send: only the
+// thread owning its connection may use it. The ThreadLocal of a SyncStatement
+// hands it to the same thread as the ThreadLocal of the SyncSqliteConnection
+// hands the connection to, which debug builds check on every access. That is
+// not always the thread that prepared it, since both pass the entries of an
+// exited thread on to the next new one. Besides that, it is only moved to be
+// dropped with the SyncStatement.
+struct SendStatement<'a> {
+    stmt: UnsafeCell<Statement<'a>>,
+    conn: &'a Connection,
+    // Dropped after the statement is finalized.
+    _count: StatementCount,
+}
 
 unsafe impl<'a> Send for SendStatement<'a> {}
 
+impl<'a> SendStatement<'a> {
+    fn get(&self) -> &Statement<'a> {
+        unsafe { &*self.stmt.get() }
+    }
+
+    // Binding and stepping need `&mut Statement` while SyncStatement only
+    // has `&self`. The caller must not keep another reference to the
+    // statement alive while using the result; since the statement never
+    // leaves its thread, that only depends on the calling thread.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get_mut(&self) -> &mut Statement<'a> {
+        &mut *self.stmt.get()
+    }
+}
+
+/// A statement that can be shared between threads, each of which runs its own
+/// copy on its own connection. It borrows the `SyncSqliteConnection` it was
+/// prepared from, so that handle, and with it the database, outlives the
+/// statement:
+///
+/// ```compile_fail,E0505
+/// let conn = inmemory_sqlite::SyncSqliteConnection::new().unwrap();
+/// let stmt = conn.prepare("SELECT 1").unwrap();
+/// drop(conn);
+/// stmt.query_scalar::<i64, _>([]).unwrap();
+/// ```
 pub struct SyncStatement<'conn> {
     conn: &'conn SyncSqliteConnection,
     stmt: ThreadLocal<SendStatement<'conn>>,
@@ -111,70 +1007,305 @@ impl<'conn> SyncStatement<'conn> {
         Result::Ok(this)
     }
 
-    fn try_get(&self) -> Result<&Statement<'_>> {
+    fn try_get_local(&self) -> Result<&SendStatement<'conn>> {
         self.stmt
             .get_or_try(|| {
-                self.conn
-                    .try_get()
-                    .and_then(|conn| conn.prepare(&self.sql).map(|stmt| SendStatement(stmt)))
+                self.conn.try_get().and_then(|conn| {
+                    conn.prepare(&self.sql).map(|stmt| SendStatement {
+                        stmt: UnsafeCell::new(stmt),
+                        conn,
+                        _count: StatementCount::new(self.conn.local().slot.clone()),
+                    })
+                })
+            })
+            .inspect(|local| {
+                debug_assert!(
+                    self.conn.owns(local.conn),
+                    "statement used by a thread other than the one owning its connection"
+                );
             })
-            .map(|ss| &ss.0)
     }
 
+    fn try_get(&self) -> Result<&Statement<'conn>> {
+        self.try_get_local().map(SendStatement::get)
+    }
+
+    // See SendStatement::get_mut.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn try_get_mut(&self) -> Result<&mut Statement<'conn>> {
+        self.try_get_local().map(|ss| ss.get_mut())
+    }
+
+    // Runs `f`, reporting it to the connection's metrics sink and, with the
+    // tracing feature, inside a DEBUG span carrying the SQL, the thread (each
+    // thread has its own connection) and the elapsed time.
+    fn instrument<T>(
+        &self,
+        operation: &'static str,
+        rows: fn(&T) -> usize,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "sqlite",
+            operation,
+            sql = %self.sql,
+            thread = ?std::thread::current().id(),
+            elapsed_us = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        #[cfg(not(feature = "tracing"))]
+        let _ = operation;
+
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+
+        #[cfg(feature = "tracing")]
+        span.record("elapsed_us", elapsed.as_micros() as u64);
+
+        if let Some(metrics) = &self.conn.metrics {
+            match &result {
+                Result::Ok(value) => metrics.on_execute(&self.sql, elapsed, rows(value)),
+                Result::Err(err) => metrics.on_error(&self.sql, err),
+            }
+        }
+        result
+    }
+
+    /// Binds `params`, positional (`params![...]`, arrays, tuples, `()`) or
+    /// named (`named_params! {...}`), and executes the statement.
     pub fn execute<P>(&self, params: P) -> Result<usize>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.execute(params)
+        self.instrument(
+            "execute",
+            |changed| *changed,
+            || unsafe { self.try_get_mut()? }.execute(params),
+        )
     }
 
     pub fn execute_named(&self, params: &[(&str, &dyn ToSql)]) -> Result<usize> {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.execute_named(params)
+        self.execute(params)
+    }
+
+    /// Like `execute_named`, taking the values from a map keyed by parameter
+    /// name with or without its prefix, e.g. `"id"` or `":id"`. Fails with
+    /// `Error::InvalidParameterName` if a parameter has no value in the map.
+    pub fn execute_map(&self, params: &HashMap<String, Value>) -> Result<usize> {
+        let params = self.params_from_map(params)?;
+        let params: Vec<(&str, &dyn ToSql)> = params
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        self.execute_named(&params)
+    }
+
+    /// Like `query_collect_named`, taking the values from a map like
+    /// `execute_map` does.
+    pub fn query_collect_map<T, F>(&self, params: &HashMap<String, Value>, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        let params = self.params_from_map(params)?;
+        let params: Vec<(&str, &dyn ToSql)> = params
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        self.query_collect_named(&params, f)
+    }
+
+    // Looks up the value of every parameter of the statement in the map.
+    fn params_from_map<'a>(
+        &self,
+        params: &'a HashMap<String, Value>,
+    ) -> Result<Vec<(String, &'a dyn ToSql)>> {
+        let stmt = self.try_get()?;
+        (1..=stmt.parameter_count())
+            .map(|idx| {
+                let name = stmt.parameter_name(idx).unwrap_or("?");
+                params
+                    .get(name)
+                    .or_else(|| params.get(&name[1..]))
+                    .map(|value| (name.to_owned(), value as &dyn ToSql))
+                    .ok_or_else(|| Error::InvalidParameterName(name.to_owned()))
+            })
+            .collect()
     }
 
     pub fn exists<P>(&self, params: P) -> Result<bool>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.exists(params)
+        self.instrument(
+            "exists",
+            |_| 0,
+            || unsafe { self.try_get_mut()? }.exists(params),
+        )
     }
 
     pub fn insert<P>(&self, params: P) -> Result<i64>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
+    {
+        self.instrument(
+            "insert",
+            |_| 1,
+            || unsafe { self.try_get_mut()? }.insert(params),
+        )
+    }
+
+    /// Executes the statement and returns the number of changed rows together
+    /// with the rowid of the row it inserted, read from the connection the
+    /// statement ran on. Both are read right after the statement, and other
+    /// threads use their own connections, so neither can be overwritten in
+    /// between. The rowid is `None` if the statement inserted no row, e.g. an
+    /// UPDATE or DELETE, or an `INSERT OR IGNORE` that skipped its row; the
+    /// connection's last inserted rowid then still belongs to an earlier
+    /// statement and is left unchanged. A row inserted with the explicit rowid
+    /// `i64::MIN` is reported as `None` as well.
+    pub fn execute_returning_rowid<P>(&self, params: P) -> Result<(usize, Option<i64>)>
+    where
+        P: Params,
+    {
+        let conn = self.try_get_local()?.conn;
+        let previous = conn.last_insert_rowid();
+        // Rowids are only negative when given explicitly, so an unchanged
+        // sentinel means nothing was inserted.
+        unsafe { ffi::sqlite3_set_last_insert_rowid(conn.handle(), i64::MIN) };
+        let changed = self.execute(params);
+        let rowid = conn.last_insert_rowid();
+        if rowid == i64::MIN {
+            unsafe { ffi::sqlite3_set_last_insert_rowid(conn.handle(), previous) };
+        }
+        let changed = changed?;
+        Result::Ok((changed, Some(rowid).filter(|&rowid| rowid != i64::MIN)))
+    }
+
+    /// Executes an INSERT statement and returns the rowid of the inserted row
+    /// together with the number of changed rows, both read from the
+    /// connection the statement ran on right after it. Fails with
+    /// `StatementChangedRows(0)` if no row was inserted, e.g. when
+    /// `INSERT OR IGNORE` skipped its row.
+    pub fn execute_insert<P>(&self, params: P) -> Result<(i64, usize)>
+    where
+        P: Params,
+    {
+        match self.execute_returning_rowid(params)? {
+            (changed, Some(rowid)) => Result::Ok((rowid, changed)),
+            (changed, None) => Result::Err(Error::StatementChangedRows(changed)),
+        }
+    }
+
+    /// Executes the statement, retrying up to `max_retries` times while it fails
+    /// with `SQLITE_BUSY` or `SQLITE_LOCKED`. The wait between attempts starts
+    /// at 1ms and doubles each time, up to one second.
+    pub fn execute_with_retry<P>(&self, params: P, max_retries: u32) -> Result<usize>
+    where
+        P: Params + Clone,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.execute(params.clone()) {
+                Result::Err(ref err) if is_busy(err) && attempt < max_retries => {
+                    std::thread::sleep(Duration::from_millis(1 << attempt.min(10)));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Executes a statement with a `RETURNING` clause and maps the rows it
+    /// returns, which may be none.
+    pub fn execute_returning<T, P, F>(&self, params: P, f: F) -> Result<Vec<T>>
+    where
+        P: Params,
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        self.query_collect(params, f)
+    }
+
+    /// Runs an `INSERT ... RETURNING` statement and maps the row it returns,
+    /// e.g. to read back a generated id and default values. Fails with
+    /// `SQLITE_MISUSE`, without running the statement, if it returns no
+    /// columns because the SQL has no RETURNING clause.
+    pub fn insert_returning<T, P, F>(&self, params: P, f: F) -> Result<T>
+    where
+        P: Params,
+        F: FnOnce(&Row<'_>) -> Result<T>,
+    {
+        // Without RETURNING the statement has no columns. This is checked on
+        // the prepared statement, before it runs and changes anything.
+        if self.try_get()?.column_count() == 0 {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some("insert_returning SQL must have a RETURNING clause".to_owned()),
+            ));
+        }
+        self.query_row(params, f)
+    }
+
+    /// Executes the statement and fails with
+    /// `InMemoryError::UnexpectedChangedRows` if it did not change exactly
+    /// `expected` rows. The changes are not undone, so run it inside a
+    /// transaction to discard them on error.
+    pub fn execute_expect<P>(&self, params: P, expected: usize) -> result::Result<(), InMemoryError>
+    where
+        P: Params,
+    {
+        match self.execute(params)? {
+            actual if actual == expected => Result::Ok(()),
+            actual => Result::Err(InMemoryError::UnexpectedChangedRows { expected, actual }),
+        }
+    }
+
+    /// Like `execute`, but fails with `Error::InvalidParameterCount`, naming
+    /// `parameter_count()` as the number needed, before the statement runs if
+    /// the number of positional values differs from it. With too many values,
+    /// binding stops at the first extra one, so the count given is one more
+    /// than the number needed. Named parameters are bound by name instead: a
+    /// name the SQL lacks fails with `Error::InvalidParameterName`.
+    pub fn execute_checked<P>(&self, params: P) -> Result<usize>
+    where
+        P: Params,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.insert(params)
+        let expected = self.parameter_count();
+        match self.execute(params) {
+            Result::Err(Error::InvalidParameterCount(given, _)) => {
+                Result::Err(Error::InvalidParameterCount(given, expected))
+            }
+            result => result,
+        }
     }
 
     pub fn query<P>(&self, params: P) -> Result<Rows<'_>>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query(params)
+        self.instrument(
+            "query",
+            |_| 0,
+            || unsafe { self.try_get_mut()? }.query(params),
+        )
     }
 
     pub fn query_named(&self, params: &[(&str, &dyn ToSql)]) -> Result<Rows<'_>> {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_named(params)
+        self.query(params)
     }
 
     pub fn query_map<T, P, F>(&self, params: P, f: F) -> Result<MappedRows<'_, F>>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
         F: FnMut(&Row<'_>) -> Result<T>,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_map(params, f)
+        self.instrument(
+            "query_map",
+            |_| 0,
+            || unsafe { self.try_get_mut()? }.query_map(params, f),
+        )
     }
 
     pub fn query_map_named<T, F>(
@@ -185,19 +1316,47 @@ impl<'conn> SyncStatement<'conn> {
     where
         F: FnMut(&Row<'_>) -> Result<T>,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_map_named(params, f)
+        self.query_map(params, f)
+    }
+
+    /// Maps every row and collects the results, stopping at the first error.
+    pub fn query_collect<T, P, F>(&self, params: P, f: F) -> Result<Vec<T>>
+    where
+        P: Params,
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        self.query_map(params, f)?.collect()
+    }
+
+    /// Like `query_collect`, also returning the number of rows.
+    pub fn query_vec_counted<T, P, F>(&self, params: P, f: F) -> Result<(Vec<T>, usize)>
+    where
+        P: Params,
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        let rows = self.query_collect(params, f)?;
+        let count = rows.len();
+        Result::Ok((rows, count))
+    }
+
+    pub fn query_collect_named<T, F>(&self, params: &[(&str, &dyn ToSql)], f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        self.query_map_named(params, f)?.collect()
     }
 
     pub fn query_and_then<T, E, P, F>(&self, params: P, f: F) -> Result<AndThenRows<'_, F>>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
         E: convert::From<Error>,
         F: FnMut(&Row<'_>) -> result::Result<T, E>,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_and_then(params, f)
+        self.instrument(
+            "query_and_then",
+            |_| 0,
+            || unsafe { self.try_get_mut()? }.query_and_then(params, f),
+        )
     }
 
     pub fn query_and_then_named<T, E, F>(
@@ -209,47 +1368,145 @@ impl<'conn> SyncStatement<'conn> {
         E: convert::From<Error>,
         F: FnMut(&Row<'_>) -> result::Result<T, E>,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_and_then_named(params, f)
+        self.query_and_then(params, f)
     }
 
     pub fn query_row<T, P, F>(&self, params: P, f: F) -> Result<T>
     where
-        P: IntoIterator,
-        P::Item: ToSql,
+        P: Params,
+        F: FnOnce(&Row<'_>) -> Result<T>,
+    {
+        self.instrument(
+            "query_row",
+            |_| 1,
+            || unsafe { self.try_get_mut()? }.query_row(params, f),
+        )
+    }
+
+    /// Like `query_row`, with `f` returning the caller's error type. No rows
+    /// become `Error::QueryReturnedNoRows` converted into that type.
+    pub fn query_row_and_then<T, E, P, F>(&self, params: P, f: F) -> result::Result<T, E>
+    where
+        P: Params,
+        E: convert::From<Error>,
+        F: FnOnce(&Row<'_>) -> result::Result<T, E>,
+    {
+        let mut rows = self.query(params)?;
+        match rows.next()? {
+            Some(row) => f(row),
+            None => Result::Err(E::from(Error::QueryReturnedNoRows)),
+        }
+    }
+
+    /// Like `query_row`, but fails with `Error::QueryReturnedMoreThanOneRow`
+    /// if there is more than one row instead of ignoring the rest.
+    pub fn query_exactly_one<T, P, F>(&self, params: P, f: F) -> Result<T>
+    where
+        P: Params,
         F: FnOnce(&Row<'_>) -> Result<T>,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_row(params, f)
+        let mut rows = self.query(params)?;
+        let value = match rows.next()? {
+            Some(row) => f(row)?,
+            None => return Result::Err(Error::QueryReturnedNoRows),
+        };
+        match rows.next()? {
+            Some(_) => Result::Err(Error::QueryReturnedMoreThanOneRow),
+            None => Result::Ok(value),
+        }
     }
 
     pub fn query_row_named<T, F>(&self, params: &[(&str, &dyn ToSql)], f: F) -> Result<T>
     where
         F: FnOnce(&Row<'_>) -> Result<T>,
     {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.query_row_named(params, f)
+        self.query_row(params, f)
+    }
+
+    /// Returns the first column of the first row.
+    pub fn query_scalar<T, P>(&self, params: P) -> Result<T>
+    where
+        T: FromSql,
+        P: Params,
+    {
+        self.query_row(params, |row| row.get(0))
+    }
+
+    /// Like `query_scalar`, but returns `None` if there is no row or the value
+    /// is NULL, as it is for an aggregate such as `MAX` over no rows.
+    pub fn query_scalar_optional<T, P>(&self, params: P) -> Result<Option<T>>
+    where
+        T: FromSql,
+        P: Params,
+    {
+        match self.query_row(params, |row| row.get::<_, Option<T>>(0)) {
+            Result::Err(Error::QueryReturnedNoRows) => Result::Ok(None),
+            result => result,
+        }
     }
 
     pub fn parameter_index(&self, name: &str) -> Result<Option<usize>> {
-        let statement = self.try_get()?;
-        unsafe { &mut *(statement as *const _ as *mut Statement) }.parameter_index(name)
+        unsafe { self.try_get_mut()? }.parameter_index(name)
     }
 
-    pub fn force(&self) -> &Statement<'_> {
-        self.try_get()
-            .expect("ERROR: Building the prepared statement has failed!")
+    /// Resets the current thread's statement so it no longer holds an open
+    /// read. Dropping the `Rows` of a query does the same.
+    pub fn reset(&self) -> Result<()> {
+        drop(unsafe { self.try_get_mut()? }.raw_query());
+        Result::Ok(())
     }
 
-    pub fn deref(&self) -> &Statement<'_> {
+    /// Sets every parameter of the current thread's statement back to NULL.
+    /// The named variants only bind the names they are given, so clear the
+    /// bindings before a call that leaves some of them out.
+    pub fn clear_bindings(&self) -> Result<()> {
+        unsafe { self.try_get_mut()? }.clear_bindings();
+        Result::Ok(())
+    }
+
+    pub fn column_names(&self) -> Vec<String> {
         self.force()
+            .column_names()
+            .into_iter()
+            .map(String::from)
+            .collect()
     }
-}
 
-impl<'conn> Clone for SyncStatement<'conn> {
-    fn clone(&self) -> Self {
-        SyncStatement::new(self.conn, self.sql.clone())
-            .expect("ERROR: creating the sqlitet prepared statement has failed!")
+    pub fn column_count(&self) -> usize {
+        self.force().column_count()
+    }
+
+    pub fn parameter_count(&self) -> usize {
+        self.force().parameter_count()
+    }
+
+    /// Whether the statement leaves the database unchanged when run.
+    pub fn is_readonly(&self) -> bool {
+        self.force().readonly()
+    }
+
+    /// The SQL of the current thread's statement with its bound parameters
+    /// substituted in, or `None` if SQLite cannot expand it.
+    pub fn expanded_sql(&self) -> Option<String> {
+        self.try_get()
+            .ok()
+            .and_then(|statement| statement.expanded_sql())
+    }
+
+    pub fn force(&self) -> &Statement<'_> {
+        self.try_get()
+            .expect("ERROR: Building the prepared statement has failed!")
+    }
+
+    pub fn deref(&self) -> &Statement<'_> {
+        self.force()
+    }
+}
+
+impl<'conn> Clone for SyncStatement<'conn> {
+    fn clone(&self) -> Self {
+        SyncStatement::new(self.conn, self.sql.clone())
+            .expect("ERROR: creating the sqlitet prepared statement has failed!")
     }
 
     fn clone_from(&mut self, source: &Self) {
@@ -271,6 +1528,29 @@ mod test {
         let _connection = crate::SyncSqliteConnection::new().unwrap();
     }
 
+    #[test]
+    fn test_unique_names() {
+        let names = std::sync::Mutex::new(std::collections::HashSet::new());
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        let c1 = crate::SyncSqliteConnection::new().unwrap();
+                        let c2 = crate::SyncSqliteConnection::new_unique().unwrap();
+                        let mut names = names.lock().unwrap();
+                        assert!(names.insert(c1.name().clone()));
+                        assert!(names.insert(c2.name().clone()));
+                    }
+                });
+            }
+        });
+
+        let c1 = crate::SyncSqliteConnection::new_unique().unwrap();
+        let c2 = crate::SyncSqliteConnection::new_unique().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        assert!(c2.execute_batch("SELECT * FROM t").is_err());
+    }
+
     #[test]
     fn test_open() {
         let dummy = crate::SyncSqliteConnection::new().unwrap();
@@ -283,6 +1563,1319 @@ mod test {
         assert_ne!(dummy.name(), c1.name());
     }
 
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        use std::sync::{Arc, Mutex};
+
+        struct SqlVisitor<'a>(&'a mut Option<String>);
+
+        impl<'a> tracing::field::Visit for SqlVisitor<'a> {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "sql" {
+                    *self.0 = Some(format!("{:?}", value));
+                }
+            }
+        }
+
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                let mut sql = None;
+                span.record(&mut SqlVisitor(&mut sql));
+                let mut spans = self.0.lock().unwrap();
+                spans.extend(sql);
+                tracing::span::Id::from_u64(spans.len() as u64 + 1)
+            }
+
+            fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+            fn event(&self, _: &tracing::Event<'_>) {}
+
+            fn enter(&self, _: &tracing::span::Id) {}
+
+            fn exit(&self, _: &tracing::span::Id) {}
+        }
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let stmt = c1.prepare("INSERT INTO t VALUES (?)").unwrap();
+
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(spans.clone()), || {
+            stmt.execute([1]).unwrap();
+        });
+        assert_eq!(*spans.lock().unwrap(), vec!["INSERT INTO t VALUES (?)"]);
+    }
+
+    #[test]
+    fn test_column_names() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (a INTEGER, b TEXT)")
+            .unwrap();
+
+        let stmt = c1.prepare("SELECT a, b FROM t").unwrap();
+        assert_eq!(stmt.column_count(), 2);
+        assert_eq!(stmt.column_names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_parameter_count() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (a INTEGER, b TEXT, c REAL)")
+            .unwrap();
+
+        let stmt = c1
+            .prepare("INSERT INTO t (a, b, c) VALUES (?, ?, ?)")
+            .unwrap();
+        assert_eq!(stmt.parameter_count(), 3);
+    }
+
+    #[test]
+    fn test_is_readonly() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        assert!(c1.prepare("SELECT x FROM t").unwrap().is_readonly());
+        assert!(!c1
+            .prepare("INSERT INTO t VALUES (1)")
+            .unwrap()
+            .is_readonly());
+    }
+
+    #[test]
+    fn test_expanded_sql() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let stmt = c1.prepare("INSERT INTO t VALUES (?)").unwrap();
+        stmt.execute([4711]).unwrap();
+        assert_eq!(stmt.expanded_sql().unwrap(), "INSERT INTO t VALUES (4711)");
+    }
+
+    #[test]
+    fn test_query_scalar() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let count = c1.prepare("SELECT count(*) FROM t").unwrap();
+        let max = c1.prepare("SELECT max(x) FROM t").unwrap();
+        let first = c1.prepare("SELECT x FROM t WHERE x > ?").unwrap();
+        assert_eq!(
+            count.query_scalar::<i64, _>(rusqlite::params![]).unwrap(),
+            0
+        );
+        assert_eq!(
+            max.query_scalar_optional::<i64, _>(rusqlite::params![])
+                .unwrap(),
+            None
+        );
+        assert_eq!(first.query_scalar_optional::<i64, _>([0]).unwrap(), None);
+
+        c1.execute_batch("INSERT INTO t VALUES (3), (7)").unwrap();
+        assert_eq!(
+            count.query_scalar::<i64, _>(rusqlite::params![]).unwrap(),
+            2
+        );
+        assert_eq!(
+            max.query_scalar_optional::<i64, _>(rusqlite::params![])
+                .unwrap(),
+            Some(7)
+        );
+        assert_eq!(first.query_scalar_optional::<i64, _>([5]).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn test_execute_returning_rowid() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER)")
+            .unwrap();
+
+        let insert = c1.prepare("INSERT INTO t (x) VALUES (?)").unwrap();
+        assert_eq!(insert.execute_returning_rowid([10]).unwrap(), (1, Some(1)));
+        c1.reset_thread_connection();
+        c1.execute("INSERT INTO t VALUES (100, 0)", []).unwrap();
+        assert_eq!(
+            insert.execute_returning_rowid([20]).unwrap(),
+            (1, Some(101))
+        );
+
+        let ignore = c1
+            .prepare("INSERT OR IGNORE INTO t (id, x) VALUES (?, 0)")
+            .unwrap();
+        assert_eq!(ignore.execute_returning_rowid([1]).unwrap(), (0, None));
+        assert_eq!(ignore.execute_returning_rowid([5]).unwrap(), (1, Some(5)));
+
+        let update = c1.prepare("UPDATE t SET x = x + 1 WHERE id < ?").unwrap();
+        assert_eq!(update.execute_returning_rowid([100]).unwrap(), (2, None));
+        let delete = c1.prepare("DELETE FROM t WHERE id = ?").unwrap();
+        assert_eq!(delete.execute_returning_rowid([5]).unwrap(), (1, None));
+        assert_eq!(c1.last_insert_rowid(), 5);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(
+                    insert.execute_returning_rowid([30]).unwrap(),
+                    (1, Some(102))
+                );
+            });
+        });
+    }
+
+    #[test]
+    fn test_execute_insert() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER UNIQUE)")
+            .unwrap();
+
+        let insert = c1.prepare("INSERT INTO t VALUES (?, ?)").unwrap();
+        assert_eq!(insert.execute_insert([42, 1]).unwrap(), (42, 1));
+        let both = c1.prepare("INSERT INTO t (x) VALUES (?), (?)").unwrap();
+        assert_eq!(both.execute_insert([2, 3]).unwrap(), (44, 2));
+
+        let ignore = c1
+            .prepare("INSERT OR IGNORE INTO t (x) VALUES (?)")
+            .unwrap();
+        match ignore.execute_insert([1]) {
+            Err(rusqlite::Error::StatementChangedRows(0)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_execute_returning() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)")
+            .unwrap();
+
+        let insert = c1
+            .prepare("INSERT INTO t (name) VALUES (?), (?) RETURNING id, name")
+            .unwrap();
+        let rows: Vec<(i64, String)> = insert
+            .execute_returning(["a", "b"], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(rows, vec![(1, "a".to_owned()), (2, "b".to_owned())]);
+
+        let delete = c1
+            .prepare("DELETE FROM t WHERE name = ? RETURNING id")
+            .unwrap();
+        let ids: Vec<i64> = delete.execute_returning(["c"], |row| row.get(0)).unwrap();
+        assert!(ids.is_empty());
+    }
+
+    #[test]
+    fn test_insert_returning() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, status TEXT DEFAULT 'new')",
+        )
+        .unwrap();
+
+        let insert = c1
+            .prepare("INSERT INTO t (name) VALUES (?) RETURNING id, status")
+            .unwrap();
+        let (id, status): (i64, String) = insert
+            .insert_returning(["a"], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((id, status.as_str()), (1, "new"));
+        assert_eq!(
+            insert
+                .insert_returning(["b"], |row| row.get::<_, i64>(0))
+                .unwrap(),
+            2
+        );
+
+        let plain = c1.prepare("INSERT INTO t (name) VALUES (?)").unwrap();
+        assert!(plain
+            .insert_returning(["c"], |row| row.get::<_, i64>(0))
+            .is_err());
+        let literal = c1
+            .prepare("INSERT INTO t (name) VALUES ('returning')")
+            .unwrap();
+        assert!(literal
+            .insert_returning([], |row| row.get::<_, i64>(0))
+            .is_err());
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_query_collect_named() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER, name TEXT);
+             INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'a');",
+        )
+        .unwrap();
+
+        let select = c1
+            .prepare("SELECT id FROM t WHERE id >= :id AND name = :name ORDER BY id")
+            .unwrap();
+        let ids: Vec<i64> = select
+            .query_collect_named(&[(":id", &1), (":name", &"a")], |row| row.get(0))
+            .unwrap();
+        assert_eq!(ids, vec![1, 3]);
+
+        let select = c1.prepare("SELECT name FROM t WHERE id < ?").unwrap();
+        let names: Vec<String> = select.query_collect([3], |row| row.get(0)).unwrap();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_query_vec_counted() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (x INTEGER);
+             INSERT INTO t VALUES (1), (2), (3);",
+        )
+        .unwrap();
+
+        let select = c1.prepare("SELECT x FROM t WHERE x > ?").unwrap();
+        let (rows, count) = select
+            .query_vec_counted([1], |row| row.get::<_, i64>(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(count, rows.len());
+
+        let (rows, count) = select
+            .query_vec_counted([3], |row| row.get::<_, i64>(0))
+            .unwrap();
+        assert_eq!((rows.len(), count), (0, 0));
+    }
+
+    #[test]
+    fn test_param_macros() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (id INTEGER, name TEXT)")
+            .unwrap();
+
+        let insert = c1.prepare("INSERT INTO t VALUES (?, ?)").unwrap();
+        insert.execute(crate::params![1, "a"]).unwrap();
+
+        let insert = c1.prepare("INSERT INTO t VALUES (:id, :name)").unwrap();
+        insert
+            .execute_named(crate::named_params! { ":id": 2, ":name": "b" })
+            .unwrap();
+        let name = String::from("c");
+        insert
+            .execute_named(crate::bind![":id" => 3, ":name" => name])
+            .unwrap();
+
+        let select = c1.prepare("SELECT name FROM t ORDER BY id").unwrap();
+        let names: Vec<String> = select
+            .query_collect_named(crate::bind![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(names, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_execute_with_retry() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let insert = c1.prepare("INSERT INTO t VALUES (?)").unwrap();
+
+        let tx = c1
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .unwrap();
+        std::thread::scope(|s| {
+            let writer = s.spawn(|| insert.execute_with_retry([1], 20));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            tx.commit().unwrap();
+            assert_eq!(writer.join().unwrap().unwrap(), 1);
+        });
+
+        assert!(insert.execute_with_retry(["not", "two"], 5).is_err());
+    }
+
+    #[test]
+    fn test_query_exactly_one() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER, name TEXT);
+             INSERT INTO t VALUES (1, 'a'), (2, 'b'), (3, 'b');",
+        )
+        .unwrap();
+
+        let select = c1.prepare("SELECT id FROM t WHERE name = ?").unwrap();
+        let id: i64 = select.query_exactly_one(["a"], |row| row.get(0)).unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(
+            select.query_exactly_one(["c"], |row| row.get::<_, i64>(0)),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        );
+        assert_eq!(
+            select.query_exactly_one(["b"], |row| row.get::<_, i64>(0)),
+            Err(rusqlite::Error::QueryReturnedMoreThanOneRow)
+        );
+    }
+
+    #[test]
+    fn test_params_kinds() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (id INTEGER, name TEXT)")
+            .unwrap();
+
+        let positional = c1.prepare("INSERT INTO t VALUES (?, ?)").unwrap();
+        positional.execute(crate::params![1, "a"]).unwrap();
+        positional.execute((2, "b")).unwrap();
+        let named = c1.prepare("INSERT INTO t VALUES (:id, :name)").unwrap();
+        named
+            .execute(crate::named_params! { ":id": 3, ":name": "c" })
+            .unwrap();
+
+        let count = c1.prepare("SELECT count(*) FROM t").unwrap();
+        assert_eq!(count.query_row([], |row| row.get::<_, i64>(0)).unwrap(), 3);
+        assert_eq!(count.query_row((), |row| row.get::<_, i64>(0)).unwrap(), 3);
+
+        let select = c1.prepare("SELECT name FROM t WHERE id = :id").unwrap();
+        let name: String = select
+            .query_row(crate::named_params! { ":id": 2 }, |row| row.get(0))
+            .unwrap();
+        assert_eq!(name, "b");
+        let mut rows = select.query([3]).unwrap();
+        assert_eq!(
+            rows.next().unwrap().unwrap().get::<_, String>(0).unwrap(),
+            "c"
+        );
+    }
+
+    #[test]
+    fn test_execute_expect() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER, owner TEXT);
+             INSERT INTO t VALUES (1, 'a');
+             INSERT INTO t VALUES (2, 'a');",
+        )
+        .unwrap();
+
+        let stmt = c1.prepare("UPDATE t SET owner = ? WHERE id = ?").unwrap();
+        stmt.execute_expect(rusqlite::params!["b", 1], 1).unwrap();
+
+        let stmt = c1.prepare("UPDATE t SET owner = ?").unwrap();
+        match stmt.execute_expect(["c"], 1) {
+            Err(crate::InMemoryError::UnexpectedChangedRows {
+                expected: 1,
+                actual: 2,
+            }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let err = stmt.execute_expect(["d"], 3).unwrap_err();
+        assert_eq!(err.to_string(), "expected 3 changed rows, got 2");
+    }
+
+    #[test]
+    fn test_query_row_and_then() {
+        #[derive(Debug, PartialEq)]
+        enum AppError {
+            Sqlite(rusqlite::Error),
+            NegativeBalance(i64),
+        }
+
+        impl From<rusqlite::Error> for AppError {
+            fn from(err: rusqlite::Error) -> AppError {
+                AppError::Sqlite(err)
+            }
+        }
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE accounts (id INTEGER, balance INTEGER);
+             INSERT INTO accounts VALUES (1, 10), (2, -5);",
+        )
+        .unwrap();
+
+        let stmt = c1
+            .prepare("SELECT balance FROM accounts WHERE id = ?")
+            .unwrap();
+        let balance = |id: i64| {
+            stmt.query_row_and_then([id], |row| match row.get(0)? {
+                balance if balance < 0 => Err(AppError::NegativeBalance(balance)),
+                balance => Ok(balance),
+            })
+        };
+        assert_eq!(balance(1), Ok(10));
+        assert_eq!(balance(2), Err(AppError::NegativeBalance(-5)));
+        assert_eq!(
+            balance(3),
+            Err(AppError::Sqlite(rusqlite::Error::QueryReturnedNoRows))
+        );
+    }
+
+    #[test]
+    fn test_execute_map() {
+        use rusqlite::types::Value;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (id INTEGER, name TEXT)")
+            .unwrap();
+
+        let mut params = std::collections::HashMap::new();
+        params.insert("id".to_owned(), Value::Integer(1));
+        params.insert(":name".to_owned(), Value::Text("a".to_owned()));
+        let insert = c1.prepare("INSERT INTO t VALUES (:id, :name)").unwrap();
+        assert_eq!(insert.execute_map(&params).unwrap(), 1);
+
+        let select = c1.prepare("SELECT id FROM t WHERE name = :name").unwrap();
+        let ids: Vec<i64> = select.query_collect_map(&params, |row| row.get(0)).unwrap();
+        assert_eq!(ids, vec![1]);
+
+        params.remove("id");
+        assert_eq!(
+            insert.execute_map(&params),
+            Err(rusqlite::Error::InvalidParameterName(":id".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_statement_stays_on_its_thread() {
+        // Threads reuse the entries of exited threads, so a thread may get a
+        // statement another one prepared, but always with its connection.
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let insert = c1.prepare("INSERT INTO t VALUES (?)").unwrap();
+
+        let check = |x: i64| {
+            insert.execute([x]).unwrap();
+            let local = insert.try_get_local().unwrap();
+            assert!(c1.owns(local.conn));
+            assert!(std::ptr::eq(local.conn, c1.try_get().unwrap()));
+        };
+        check(0);
+        std::thread::scope(|s| {
+            for x in 1..4 {
+                s.spawn(move || check(x));
+            }
+        });
+        assert_eq!(c1.count("t", None, []).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_execute_checked() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER, y INTEGER)")
+            .unwrap();
+
+        let stmt = c1.prepare("INSERT INTO t VALUES (?, ?)").unwrap();
+        let err = stmt.execute_checked([1]).unwrap_err();
+        assert_eq!(err, rusqlite::Error::InvalidParameterCount(1, 2));
+        assert_eq!(
+            err.to_string(),
+            "Wrong number of parameters passed to query. Got 1, needed 2"
+        );
+        assert_eq!(
+            stmt.execute_checked((1, 2, 3)).unwrap_err(),
+            rusqlite::Error::InvalidParameterCount(3, 2)
+        );
+        assert_eq!(stmt.execute_checked(rusqlite::params![1, "a"]).unwrap(), 1);
+        assert_eq!(stmt.execute_checked((1, 2)).unwrap(), 1);
+
+        let named = c1.prepare("INSERT INTO t VALUES (:x, :y)").unwrap();
+        assert_eq!(named.execute_checked(&[(":x", &1), (":y", &2)]).unwrap(), 1);
+        assert!(matches!(
+            named.execute_checked(&[(":z", &1)]),
+            Err(rusqlite::Error::InvalidParameterName(_))
+        ));
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_seed() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let seed = |c: &crate::SyncSqliteConnection| {
+            c.seed("t", |conn| conn.execute_batch("INSERT INTO t VALUES (1)"))
+                .unwrap();
+        };
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| seed(&c1));
+                s.spawn(|| seed(&c1.clone()));
+            }
+        });
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let name = c1.name().clone();
+        drop(c1);
+        let c2 = crate::SyncSqliteConnection::open(name).unwrap();
+        c2.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        seed(&c2);
+        let count: i64 = c2
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_clear_bindings() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (a INTEGER, b TEXT)")
+            .unwrap();
+
+        let insert = c1.prepare("INSERT INTO t VALUES (:a, :b)").unwrap();
+        insert
+            .execute_named(&[(":a", &1), (":b", &"stale")])
+            .unwrap();
+        insert.clear_bindings().unwrap();
+        insert.execute_named(&[(":a", &2)]).unwrap();
+        insert.reset().unwrap();
+
+        let b: Option<String> = c1
+            .query_row("SELECT b FROM t WHERE a = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(b, None);
+    }
+
+    #[test]
+    fn test_statement_reuse() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (a INTEGER, b TEXT)")
+            .unwrap();
+
+        let insert = c1.prepare("INSERT INTO t VALUES (:a, :b)").unwrap();
+        let select = c1.prepare("SELECT b FROM t WHERE a = ?").unwrap();
+        for i in 0..3 {
+            insert
+                .execute_named(&[(":a", &(2 * i)), (":b", &"set")])
+                .unwrap();
+            insert.clear_bindings().unwrap();
+            insert.execute_named(&[(":a", &(2 * i + 1))]).unwrap();
+
+            let mut rows = select.query([2 * i]).unwrap();
+            let b: Option<String> = rows.next().unwrap().unwrap().get(0).unwrap();
+            assert_eq!(b.as_deref(), Some("set"));
+            drop(rows);
+            select.reset().unwrap();
+
+            let b: Option<String> = select.query_row([2 * i + 1], |row| row.get(0)).unwrap();
+            assert_eq!(b, None);
+        }
+    }
+
+    #[test]
+    fn test_prepare_many() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let stmts = c1
+            .prepare_many(&["SELECT x FROM t", "INSERT INTO t VALUES (?)"])
+            .unwrap();
+        assert_eq!(stmts.len(), 2);
+
+        let result = c1.prepare_many(&[
+            "SELECT x FROM t",
+            "SELEKT x FROM t",
+            "INSERT INTO t VALUES (?)",
+        ]);
+        match result {
+            Err(err) => assert!(err.to_string().contains("SELEKT"), "{}", err),
+            Ok(stmts) => panic!("prepared {} statements", stmts.len()),
+        }
+    }
+
+    #[test]
+    fn test_new_with_schema() {
+        const SCHEMA: &str = "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+                              CREATE TABLE orders (id INTEGER, user_id INTEGER);";
+
+        let c1 = crate::SyncSqliteConnection::new_with_schema(SCHEMA).unwrap();
+        let tables = std::thread::scope(|s| s.spawn(|| c1.tables().unwrap()).join().unwrap());
+        assert_eq!(tables, vec!["orders", "users"]);
+
+        assert!(crate::SyncSqliteConnection::new_with_schema("CREATE TABLE (x)").is_err());
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        assert_eq!(c1.integrity_check().unwrap(), vec!["ok"]);
+
+        c1.execute_batch(
+            "CREATE TABLE t (x INTEGER UNIQUE);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n LIMIT 1000)
+             INSERT INTO t SELECT i FROM n;",
+        )
+        .unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| c1.execute("DELETE FROM t WHERE x % 7 = 0", []));
+            }
+        });
+        assert_eq!(c1.integrity_check().unwrap(), vec!["ok"]);
+    }
+
+    #[test]
+    fn test_count() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (x INTEGER);
+             INSERT INTO t VALUES (1), (2), (3), (4);",
+        )
+        .unwrap();
+
+        assert_eq!(c1.count("t", None, []).unwrap(), 4);
+        assert_eq!(c1.count("t", Some("x > ?"), [2]).unwrap(), 2);
+        assert!(c1.count("t; DROP TABLE t", None, []).is_err());
+        assert_eq!(c1.count("t", None, []).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_upsert() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE kv (k TEXT PRIMARY KEY, v INTEGER)")
+            .unwrap();
+
+        let inserted = c1
+            .upsert("kv", &["k"], crate::bind!["k" => "a", "v" => 1])
+            .unwrap();
+        let updated = c1
+            .upsert("kv", &["k"], crate::bind!["k" => "a", "v" => 2])
+            .unwrap();
+        assert_eq!(inserted, updated);
+
+        let rows: Vec<(String, i64)> = c1
+            .prepare("SELECT k, v FROM kv")
+            .unwrap()
+            .query_collect([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(rows, vec![("a".to_owned(), 2)]);
+
+        assert!(c1.upsert("kv", &[], crate::bind!["k" => "a"]).is_err());
+        assert!(c1.upsert("kv", &["k"], crate::bind![]).is_err());
+
+        c1.execute_batch("CREATE TABLE wr (k TEXT PRIMARY KEY, v INTEGER) WITHOUT ROWID")
+            .unwrap();
+        match c1.upsert("wr", &["k"], crate::bind!["k" => "a", "v" => 1]) {
+            Err(rusqlite::Error::SqliteFailure(e, Some(_))) => {
+                assert_eq!(e.code, rusqlite::ErrorCode::ApiMisuse)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM wr", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let handle = c1.get_interrupt_handle().unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::Acquire) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    handle.interrupt();
+                }
+            });
+
+            let result = c1.query_row(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n)
+                 SELECT count(*) FROM n",
+                [],
+                |row| row.get::<_, i64>(0),
+            );
+            done.store(true, std::sync::atomic::Ordering::Release);
+            match result {
+                Err(rusqlite::Error::SqliteFailure(err, _)) => {
+                    assert_eq!(err.code, rusqlite::ErrorCode::OperationInterrupted)
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn test_query_page() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (x INTEGER);
+             INSERT INTO t VALUES (1), (2), (3), (4), (5);",
+        )
+        .unwrap();
+
+        let page = |offset| {
+            c1.query_page("SELECT x FROM t ORDER BY x", 2, offset, |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(page(0), vec![1, 2]);
+        assert_eq!(page(2), vec![3, 4]);
+        assert_eq!(page(6), Vec::<i64>::new());
+
+        let descending = c1
+            .query_page("SELECT x FROM t ORDER BY x DESC;\n", 2, 1, |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap();
+        assert_eq!(descending, vec![4, 3]);
+        let commented = c1
+            .query_page(
+                "SELECT x FROM t ORDER BY x -- smallest first",
+                1,
+                4,
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap();
+        assert_eq!(commented, vec![5]);
+
+        for sql in &[
+            "SELECT x FROM t ORDER BY x LIMIT 3",
+            "SELECT x FROM t ORDER BY x limit 3 offset 1;",
+            "SELECT x FROM t UNION SELECT x FROM t\nLIMIT 3",
+        ] {
+            let err = c1
+                .query_page(sql, 2, 0, |row| row.get::<_, i64>(0))
+                .unwrap_err();
+            assert_eq!(
+                err.sqlite_error_code(),
+                Some(rusqlite::ErrorCode::ApiMisuse)
+            );
+        }
+
+        let notes = c1
+            .query_page(
+                "SELECT 'limit' AS \"limit\", x AS limited FROM t
+                 WHERE x IN (SELECT x FROM t ORDER BY x LIMIT 3) /* limit */
+                 ORDER BY x",
+                2,
+                1,
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            notes,
+            vec![("limit".to_owned(), 2), ("limit".to_owned(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_execute_batch_counted() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let counts = c1
+            .execute_batch_counted(
+                "CREATE TABLE t (x TEXT);
+                 INSERT INTO t VALUES ('a;b'), ('c'';d');
+                 -- not a statement; really
+                 UPDATE t SET x = x || ';' WHERE x = 'c'';d';
+                 /* also; not */ DELETE FROM t WHERE x = 'none';",
+            )
+            .unwrap();
+        assert_eq!(counts, vec![0, 2, 1, 0]);
+
+        let values: Vec<String> = c1
+            .prepare("SELECT x FROM t ORDER BY x")
+            .unwrap()
+            .query_collect(crate::params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(values, vec!["a;b", "c';d;"]);
+    }
+
+    #[test]
+    fn test_with_cached() {
+        use rusqlite::StatementStatus;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let insert = |x: i64| {
+            c1.with_cached("INSERT INTO t VALUES (?)", |stmt| {
+                let runs = stmt.get_status(StatementStatus::Run);
+                stmt.execute([x])?;
+                Ok(runs)
+            })
+            .unwrap()
+        };
+        assert_eq!(insert(1), 0);
+        assert_eq!(insert(2), 1);
+
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(insert(3), 0));
+        });
+        let mut stmt = c1.prepare_cached("INSERT INTO t VALUES (?)").unwrap();
+        assert_eq!(stmt.get_status(StatementStatus::Run), 2);
+        stmt.execute([4]).unwrap();
+    }
+
+    #[test]
+    fn test_reset_thread_connection() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let before = &*c1 as *const rusqlite::Connection;
+
+        c1.reset_thread_connection();
+        c1.health_check().unwrap();
+
+        assert_ne!(before, &*c1 as *const rusqlite::Connection);
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_connection_count() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        assert_eq!(c1.connection_count(), 1);
+
+        let barrier = std::sync::Barrier::new(4);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    c1.health_check().unwrap();
+                    barrier.wait();
+                });
+            }
+        });
+        assert_eq!(c1.connection_count(), 5);
+    }
+
+    #[test]
+    fn test_open_connection_count() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+
+        // The threads run at the same time, so none of them takes over the
+        // connection of a thread that already exited.
+        let barrier = std::sync::Barrier::new(3);
+        std::thread::scope(|s| {
+            let threads: Vec<_> = (0..3)
+                .map(|_| {
+                    s.spawn(|| {
+                        c1.health_check().unwrap();
+                        barrier.wait();
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        });
+        assert_eq!(c1.open_connection_count(), 4);
+
+        c1.reset_thread_connection();
+        c1.health_check().unwrap();
+        assert_eq!(c1.open_connection_count(), 5);
+        assert_eq!(c1.connection_count(), 4);
+    }
+
+    #[test]
+    fn test_table_hash() {
+        let schema = "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+                      CREATE TABLE tags (tag TEXT PRIMARY KEY, n INTEGER) WITHOUT ROWID;
+                      INSERT INTO users VALUES (1, 'a'), (2, 'b');
+                      INSERT INTO tags VALUES ('x', 1), ('y', NULL);";
+        let c1 = crate::SyncSqliteConnection::new_with_schema(schema).unwrap();
+        let c2 = crate::SyncSqliteConnection::new_with_schema(schema).unwrap();
+        assert_eq!(
+            c1.table_hash("users").unwrap(),
+            c2.table_hash("users").unwrap()
+        );
+        assert_eq!(
+            c1.table_hash("tags").unwrap(),
+            c2.table_hash("tags").unwrap()
+        );
+
+        c2.execute("UPDATE users SET name = 'c' WHERE id = 2", [])
+            .unwrap();
+        assert_ne!(
+            c1.table_hash("users").unwrap(),
+            c2.table_hash("users").unwrap()
+        );
+        assert_eq!(
+            c1.table_hash("tags").unwrap(),
+            c2.table_hash("tags").unwrap()
+        );
+
+        assert!(c1.table_hash("missing").is_err());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let schema = "CREATE TABLE t (x INTEGER, y TEXT);
+                      INSERT INTO t VALUES (1, 'a');
+                      INSERT INTO t VALUES (2, 'b');";
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(schema).unwrap();
+        let c2 = crate::SyncSqliteConnection::new().unwrap();
+        c2.execute_batch(schema).unwrap();
+        let c3 = crate::SyncSqliteConnection::new().unwrap();
+        c3.execute_batch(schema).unwrap();
+        c3.execute("UPDATE t SET y = 'c' WHERE x = 2", []).unwrap();
+
+        assert_eq!(c1.content_hash().unwrap(), c2.content_hash().unwrap());
+        assert_ne!(c1.content_hash().unwrap(), c3.content_hash().unwrap());
+    }
+
+    #[test]
+    fn test_clear_connections() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let before = &*c1 as *const rusqlite::Connection;
+
+        c1.clear_connections();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let count: i64 = c1
+                    .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(count, 1);
+            });
+        });
+        assert_ne!(before, &*c1 as *const rusqlite::Connection);
+    }
+
+    #[test]
+    fn test_fork() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+
+        let fork = c1.fork().unwrap();
+        assert_ne!(c1.name(), fork.name());
+        fork.execute("INSERT INTO t VALUES (2)", []).unwrap();
+
+        let count = |conn: &crate::SyncSqliteConnection| -> i64 {
+            conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count(&c1), 1);
+        assert_eq!(count(&fork), 2);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.execute("DELETE FROM t", []).unwrap();
+                assert_eq!(count(&fork), 2);
+            });
+        });
+        assert_eq!(count(&c1), 0);
+    }
+
+    #[test]
+    fn test_blob_open() {
+        use std::io::{Read, Write};
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE files (data BLOB)").unwrap();
+        c1.execute("INSERT INTO files VALUES (zeroblob(?))", [1024 * 1024])
+            .unwrap();
+        let rowid = c1.last_insert_rowid();
+
+        let chunk: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut blob = c1
+            .blob_open(rusqlite::MAIN_DB, "files", "data", rowid, false)
+            .unwrap();
+        for _ in 0..16 {
+            blob.write_all(&chunk).unwrap();
+        }
+        drop(blob);
+
+        let mut blob = c1
+            .blob_open(rusqlite::MAIN_DB, "files", "data", rowid, true)
+            .unwrap();
+        let mut buffer = vec![0u8; chunk.len()];
+        for _ in 0..16 {
+            blob.read_exact(&mut buffer).unwrap();
+            assert_eq!(buffer, chunk);
+        }
+        assert_eq!(blob.read(&mut buffer).unwrap(), 0);
+        drop(blob);
+
+        c1.execute_batch(
+            "CREATE TEMP TABLE scratch (data BLOB); INSERT INTO scratch VALUES (x'0102');",
+        )
+        .unwrap();
+        let rowid = c1.last_insert_rowid();
+        let mut blob = c1
+            .blob_open("temp", "scratch", "data", rowid, true)
+            .unwrap();
+        let mut data = Vec::new();
+        blob.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+    }
+
+    #[test]
+    fn test_file_shared_across_processes() {
+        let path = std::env::temp_dir().join(format!(
+            "inmemory-sqlite-{}-file-shared.db",
+            std::process::id()
+        ));
+        let _ignore = std::fs::remove_file(&path);
+
+        let c1 = crate::SyncSqliteConnection::new_file_shared(&path).unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1), (2);")
+            .unwrap();
+
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test::file_shared_child", "--ignored"])
+            .env("INMEMORY_SQLITE_CHILD_DB", &path)
+            .status()
+            .unwrap();
+
+        drop(c1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_reset_counter() {
+        // The reset would hand out the names of other tests' databases, so it
+        // runs in a process of its own.
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test::reset_counter_child", "--ignored"])
+            .env("INMEMORY_SQLITE_RESET_COUNTER", "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    // Run by test_reset_counter in a child process.
+    #[test]
+    #[ignore]
+    fn reset_counter_child() {
+        if std::env::var_os("INMEMORY_SQLITE_RESET_COUNTER").is_none() {
+            return;
+        }
+
+        crate::reset_counter();
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        assert_eq!(c1.name(), "shared_0");
+        drop(c1);
+        crate::reset_counter();
+        assert_eq!(
+            crate::SyncSqliteConnection::new().unwrap().name(),
+            "shared_0"
+        );
+    }
+
+    // Run by test_file_shared_across_processes in a child process.
+    #[test]
+    #[ignore]
+    fn file_shared_child() {
+        let path = match std::env::var_os("INMEMORY_SQLITE_CHILD_DB") {
+            Some(path) => path,
+            None => return,
+        };
+
+        let c1 = crate::SyncSqliteConnection::new_file_shared(path).unwrap();
+        let sum: i64 = c1
+            .query_row("SELECT sum(x) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(sum, 3);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| c1.execute("INSERT INTO t VALUES (2)", []));
+        });
+
+        let name = c1.name().clone();
+        c1.shutdown().unwrap();
+
+        let c2 = crate::SyncSqliteConnection::open(name).unwrap();
+        let tables: i64 = c2
+            .query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn test_shutdown_with_clone() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let c2 = c1.clone();
+
+        c1.shutdown().unwrap();
+        let count: i64 = c2
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let name = c2.name().clone();
+        c2.shutdown().unwrap();
+        assert!(crate::SyncSqliteConnection::open_existing(name).is_err());
+    }
+
+    #[test]
+    fn test_open_with_flags() {
+        use rusqlite::OpenFlags;
+
+        let name = String::from("test_open_with_flags");
+        let c1 = crate::SyncSqliteConnection::open_with_flags(
+            name.clone(),
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_PRIVATE_CACHE,
+        )
+        .unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+
+        let plain = crate::open_shared_with_flags(&name, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.execute("INSERT INTO t VALUES (2)", []).unwrap();
+            });
+        });
+        let count: i64 = plain
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_open_named() {
+        let shared =
+            crate::SyncSqliteConnection::open_named("test_open_named".to_owned(), true).unwrap();
+        let private =
+            crate::SyncSqliteConnection::open_named("test_open_named".to_owned(), false).unwrap();
+        private.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        shared
+            .execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let count: i64 = shared
+                    .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(count, 1);
+                assert!(private.execute("INSERT INTO t VALUES (1)", []).is_err());
+            });
+        });
+        let count: i64 = private
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_uri() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let uri = c1.uri();
+        assert!(uri.contains(c1.name().as_str()));
+        assert!(uri.contains("mode=memory"));
+        assert!(uri.contains("cache=shared"));
+
+        let other = crate::new_shared().unwrap();
+        other.execute("ATTACH DATABASE ? AS c1", [&uri]).unwrap();
+        let count: i64 = other
+            .query_row("SELECT count(*) FROM c1.t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_open_existing() {
+        let name = String::from("test_open_existing");
+        let c1 = crate::SyncSqliteConnection::open(name.clone()).unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let c2 = c1.clone();
+        drop(c1);
+
+        let c3 = crate::SyncSqliteConnection::open_existing(name.clone()).unwrap();
+        assert_eq!(c3.tables().unwrap(), vec!["t"]);
+        drop(c2);
+        c3.shutdown().unwrap();
+
+        match crate::SyncSqliteConnection::open_existing(name.clone()) {
+            Err(crate::InMemoryError::DatabaseEvicted(evicted)) => assert_eq!(evicted, name),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("the database was not evicted"),
+        }
+        let c4 = crate::SyncSqliteConnection::open(name).unwrap();
+        assert!(c4.tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_handle_keeps_database_alive() {
+        let name = String::from("test_handle_keeps_database_alive");
+        let mut c1 = crate::SyncSqliteConnection::open(name.clone()).unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let c2 = crate::SyncSqliteConnection::open_existing(name.clone()).unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| c2.health_check().unwrap());
+        });
+        drop(c1.clone());
+        c1.close_idle_connections(std::time::Duration::from_millis(0))
+            .unwrap();
+        c2.shutdown().unwrap();
+
+        // c1 is the last handle, with a single connection left.
+        assert_eq!(c1.open_connection_count(), 1);
+        c1.health_check().unwrap();
+        assert_eq!(c1.fork().unwrap().tables().unwrap(), vec!["t"]);
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(c1.tables().unwrap(), vec!["t"]));
+        });
+
+        c1.shutdown().unwrap();
+        match crate::SyncSqliteConnection::open_existing(name.clone()) {
+            Err(crate::InMemoryError::DatabaseEvicted(evicted)) => assert_eq!(evicted, name),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("the database was not evicted"),
+        }
+    }
+
+    #[test]
+    fn test_new_with_cache() {
+        use crate::CacheMode;
+
+        let count = |conn: &crate::SyncSqliteConnection| -> i64 {
+            conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                .unwrap()
+        };
+        let shared = crate::SyncSqliteConnection::new_with_cache(CacheMode::Shared).unwrap();
+        let private = crate::SyncSqliteConnection::new_with_cache(CacheMode::Private).unwrap();
+        for conn in [&shared, &private] {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS t (x INTEGER); INSERT INTO t VALUES (1);",
+            )
+            .unwrap();
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(count(&shared), 1);
+                private
+                    .execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1), (2);")
+                    .unwrap();
+                assert_eq!(count(&private), 2);
+            });
+        });
+        assert_eq!(count(&private), 1);
+    }
+
     #[test]
     fn test_clone() {
         let c1 = crate::SyncSqliteConnection::new().unwrap();
@@ -0,0 +1,99 @@
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+/// Sets the soft limit on the memory SQLite allocates across the whole
+/// process and returns the previous limit. Past the limit SQLite frees cache
+/// where it can; a negative value only reads the limit and 0 removes it.
+pub fn set_soft_heap_limit(bytes: i64) -> i64 {
+    unsafe { ffi::sqlite3_soft_heap_limit64(bytes) }
+}
+
+impl SyncSqliteConnection {
+    /// Bytes of memory currently allocated by SQLite. This is a process-wide
+    /// figure covering every database and connection, not just this one.
+    pub fn memory_used(&self) -> i64 {
+        unsafe { ffi::sqlite3_memory_used() }
+    }
+
+    /// The most memory SQLite has had allocated at once, process-wide like
+    /// `memory_used`. With `reset`, the mark starts over from the current
+    /// usage after returning.
+    pub fn memory_highwater(&self, reset: bool) -> i64 {
+        unsafe { ffi::sqlite3_memory_highwater(reset as i32) }
+    }
+
+    /// Runs `PRAGMA optimize` on the current thread's connection, which
+    /// updates the query planner statistics where they are likely stale.
+    pub fn optimize(&self) -> Result<()> {
+        self.try_get()?.execute_batch("PRAGMA optimize")
+    }
+
+    /// Runs `VACUUM` on the current thread's connection, rebuilding the
+    /// database to release the pages freed by deletes. Fails with
+    /// `SQLITE_MISUSE` while the thread has a transaction open, which SQLite
+    /// does not allow VACUUM in.
+    pub fn vacuum(&self) -> Result<()> {
+        let conn = self.try_get()?;
+        if !conn.is_autocommit() {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some("cannot VACUUM inside a transaction".to_owned()),
+            ));
+        }
+        conn.execute_batch("VACUUM")
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_memory_used() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (data BLOB)").unwrap();
+
+        let before = c1.memory_used();
+        c1.execute_batch(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n LIMIT 16384)
+             INSERT INTO t SELECT randomblob(1024) FROM n",
+        )
+        .unwrap();
+        let after = c1.memory_used();
+        assert!(after > before + 8 * 1024 * 1024);
+        assert!(c1.memory_highwater(false) >= after);
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (data BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n LIMIT 1000)
+             INSERT INTO t SELECT randomblob(1024) FROM n;
+             DELETE FROM t WHERE rowid > 10;",
+        )
+        .unwrap();
+        let page_count = || -> i64 {
+            c1.query_row("PRAGMA page_count", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        let before = page_count();
+        c1.optimize().unwrap();
+        c1.vacuum().unwrap();
+        assert!(page_count() < before);
+        assert_eq!(c1.count("t", None, []).unwrap(), 10);
+
+        let tx = c1.transaction().unwrap();
+        assert!(c1.vacuum().is_err());
+        tx.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_set_soft_heap_limit() {
+        let original = crate::set_soft_heap_limit(1 << 30);
+        assert_eq!(crate::set_soft_heap_limit(2 << 30), 1 << 30);
+        assert_eq!(crate::set_soft_heap_limit(-1), 2 << 30);
+        crate::set_soft_heap_limit(original);
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+/// Receives the outcome of every statement run through a `SyncStatement` of
+/// a connection. Calls come from whichever thread ran the statement. `rows`
+/// is the number of changed rows for `execute`, 1 for `insert` and
+/// `query_row`, and 0 for `exists` and for `query`, `query_map` and
+/// `query_and_then`, whose rows are only read afterwards.
+pub trait MetricsSink: Send + Sync {
+    fn on_execute(&self, sql: &str, duration: Duration, rows: usize);
+
+    fn on_error(&self, sql: &str, error: &Error);
+}
+
+impl SyncSqliteConnection {
+    pub fn with_metrics(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = Some(sink);
+        self
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_metrics() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Default)]
+        struct Counter {
+            executes: AtomicUsize,
+            rows: AtomicUsize,
+            errors: AtomicUsize,
+        }
+
+        impl crate::MetricsSink for Counter {
+            fn on_execute(&self, _: &str, _: std::time::Duration, rows: usize) {
+                self.executes.fetch_add(1, Ordering::SeqCst);
+                self.rows.fetch_add(rows, Ordering::SeqCst);
+            }
+
+            fn on_error(&self, _: &str, _: &rusqlite::Error) {
+                self.errors.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let counter = Arc::new(Counter::default());
+        let c1 = crate::SyncSqliteConnection::new()
+            .unwrap()
+            .with_metrics(counter.clone());
+        c1.execute_batch("CREATE TABLE t (x INTEGER UNIQUE)")
+            .unwrap();
+
+        let insert = c1.prepare("INSERT INTO t VALUES (?)").unwrap();
+        insert.execute([1]).unwrap();
+        assert_eq!(counter.executes.load(Ordering::SeqCst), 1);
+        assert_eq!(counter.rows.load(Ordering::SeqCst), 1);
+
+        assert!(insert.execute([1]).is_err());
+        assert_eq!(counter.errors.load(Ordering::SeqCst), 1);
+
+        insert.insert([2]).unwrap();
+        let select = c1.prepare("SELECT x FROM t WHERE x > ?").unwrap();
+        assert!(select.exists([0]).unwrap());
+        assert_eq!(
+            select
+                .query_map([0], |row| row.get::<_, i64>(0))
+                .unwrap()
+                .count(),
+            2
+        );
+        let values = select
+            .query_and_then([0], |row| row.get::<_, i64>(0))
+            .unwrap();
+        assert_eq!(values.count(), 2);
+        assert_eq!(counter.executes.load(Ordering::SeqCst), 5);
+        assert_eq!(counter.rows.load(Ordering::SeqCst), 2);
+    }
+}
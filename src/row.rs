@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use rusqlite::types::{FromSql, FromSqlError, Value, ValueRef};
+use rusqlite::*;
+
+use crate::SyncStatement;
+
+pub trait RowExt {
+    /// Like `Row::get`, but maps SQL NULL to `None`. An invalid column still
+    /// returns an error.
+    fn get_opt<I, T>(&self, idx: I) -> Result<Option<T>>
+    where
+        I: RowIndex + Copy,
+        T: FromSql;
+}
+
+impl<'stmt> RowExt for Row<'stmt> {
+    fn get_opt<I, T>(&self, idx: I) -> Result<Option<T>>
+    where
+        I: RowIndex + Copy,
+        T: FromSql,
+    {
+        match self.get_ref(idx)? {
+            ValueRef::Null => Result::Ok(None),
+            _ => self.get(idx).map(Some),
+        }
+    }
+}
+
+/// An owned copy of a row's columns and values, which unlike `Row` can be
+/// kept after the query and sent to other threads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowSnapshot {
+    values: Vec<(String, Value)>,
+}
+
+impl RowSnapshot {
+    /// Converts the value of the column with the given name, failing like
+    /// `Row::get` does.
+    pub fn get<T: FromSql>(&self, name: &str) -> Result<T> {
+        let idx = self
+            .values
+            .iter()
+            .position(|(column, _)| column == name)
+            .ok_or_else(|| Error::InvalidColumnName(name.to_owned()))?;
+        self.get_index(idx)
+    }
+
+    pub fn get_index<T: FromSql>(&self, idx: usize) -> Result<T> {
+        let (name, value) = self.values.get(idx).ok_or(Error::InvalidColumnIndex(idx))?;
+        T::column_result(ValueRef::from(value)).map_err(|err| match err {
+            FromSqlError::InvalidType => {
+                Error::InvalidColumnType(idx, name.clone(), value.data_type())
+            }
+            FromSqlError::OutOfRange(i) => Error::IntegralValueOutOfRange(idx, i),
+            FromSqlError::Utf8Error(err) => Error::Utf8Error(idx, err),
+            err => Error::FromSqlConversionFailure(idx, value.data_type(), Box::new(err)),
+        })
+    }
+
+    pub fn values(&self) -> &[(String, Value)] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<(String, Value)> {
+        self.values
+    }
+}
+
+impl<'conn> SyncStatement<'conn> {
+    /// Runs the query and copies every row into a `RowSnapshot`.
+    pub fn query_snapshots<P>(&self, params: P) -> Result<Vec<RowSnapshot>>
+    where
+        P: Params,
+    {
+        let names = self.column_names();
+        let mut rows = self.query(params)?;
+        let mut snapshots = Vec::new();
+        while let Some(row) = rows.next()? {
+            let values = names
+                .iter()
+                .enumerate()
+                .map(|(idx, name)| Result::Ok((name.clone(), row.get(idx)?)))
+                .collect::<Result<_>>()?;
+            snapshots.push(RowSnapshot { values });
+        }
+        Result::Ok(snapshots)
+    }
+
+    /// Runs the query and returns the values of every row, in column order.
+    pub fn query_value_rows<P>(&self, params: P) -> Result<Vec<Vec<Value>>>
+    where
+        P: Params,
+    {
+        let column_count = self.column_count();
+        let rows = self.query_map(params, |row| {
+            (0..column_count).map(|idx| row.get(idx)).collect()
+        })?;
+        rows.collect()
+    }
+
+    /// Runs the query and returns every row as a map from column name to
+    /// value. If several columns have the same name, the last one wins; use
+    /// `AS` to tell them apart.
+    pub fn query_named_rows<P>(&self, params: P) -> Result<Vec<HashMap<String, Value>>>
+    where
+        P: Params,
+    {
+        let names = self.column_names();
+        let rows = self.query_map(params, |row| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(idx, name)| Result::Ok((name.clone(), row.get(idx)?)))
+                .collect()
+        })?;
+        rows.collect()
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_get_opt() {
+        use crate::RowExt;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER, nick TEXT);
+             INSERT INTO t VALUES (1, 'one');
+             INSERT INTO t VALUES (2, NULL);",
+        )
+        .unwrap();
+
+        let stmt = c1.prepare("SELECT nick FROM t ORDER BY id").unwrap();
+        let nicks: Vec<Option<String>> = stmt
+            .query_map(rusqlite::params![], |row| row.get_opt(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(nicks, vec![Some("one".to_owned()), None]);
+
+        let missing = stmt.query_row(rusqlite::params![], |row| row.get_opt::<_, String>(5));
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_query_snapshots() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER, name TEXT);
+             INSERT INTO t VALUES (1, 'one'), (2, NULL);",
+        )
+        .unwrap();
+
+        let stmt = c1.prepare("SELECT id, name FROM t ORDER BY id").unwrap();
+        let snapshots = stmt.query_snapshots(rusqlite::params![]).unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for snapshot in snapshots {
+                sender.send(snapshot).unwrap();
+            }
+        });
+
+        let rows: Vec<crate::RowSnapshot> = receiver.iter().collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get::<i64>("id").unwrap(), 1);
+        assert_eq!(rows[0].get::<String>("name").unwrap(), "one");
+        assert_eq!(rows[1].get_index::<Option<String>>(1).unwrap(), None);
+        assert!(rows[1].get::<i64>("missing").is_err());
+        assert!(rows[0].get::<i64>("name").is_err());
+    }
+
+    #[test]
+    fn test_query_value_rows() {
+        use rusqlite::types::Value;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let stmt = c1
+            .prepare("SELECT 1, 2.5, 'text', x'0102', NULL UNION ALL SELECT 2, 0.0, '', x'', 3")
+            .unwrap();
+        assert_eq!(
+            stmt.query_value_rows([]).unwrap(),
+            vec![
+                vec![
+                    Value::Integer(1),
+                    Value::Real(2.5),
+                    Value::Text("text".to_owned()),
+                    Value::Blob(vec![1, 2]),
+                    Value::Null,
+                ],
+                vec![
+                    Value::Integer(2),
+                    Value::Real(0.0),
+                    Value::Text(String::new()),
+                    Value::Blob(Vec::new()),
+                    Value::Integer(3),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_named_rows() {
+        use rusqlite::types::Value;
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        let rows = c1
+            .prepare("SELECT 1 AS id, 'a' AS name, 2 AS dup, 3 AS dup")
+            .unwrap()
+            .query_named_rows([])
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+
+        let mut keys: Vec<&String> = rows[0].keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["dup", "id", "name"]);
+        assert_eq!(rows[0]["id"], Value::Integer(1));
+        assert_eq!(rows[0]["name"], Value::Text("a".to_owned()));
+        assert_eq!(rows[0]["dup"], Value::Integer(3));
+    }
+}
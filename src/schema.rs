@@ -0,0 +1,347 @@
+use std::fmt::Write;
+use std::io;
+
+use rusqlite::types::ValueRef;
+use rusqlite::*;
+
+use crate::error::write_error;
+use crate::{quote_identifier, SyncSqliteConnection};
+
+// Appends the value as an SQL literal that reads back as the same value.
+fn push_literal(sql: &mut String, value: ValueRef<'_>) {
+    match value {
+        ValueRef::Null => sql.push_str("NULL"),
+        ValueRef::Integer(i) => {
+            let _ = write!(sql, "{}", i);
+        }
+        // Debug prints the shortest representation that parses back to the
+        // same f64. SQLite reads 9e999 as infinity.
+        ValueRef::Real(f) if f.is_infinite() => {
+            sql.push_str(if f > 0.0 { "9e999" } else { "-9e999" })
+        }
+        ValueRef::Real(f) => {
+            let _ = write!(sql, "{:?}", f);
+        }
+        ValueRef::Text(text) => {
+            sql.push('\'');
+            sql.push_str(&String::from_utf8_lossy(text).replace('\'', "''"));
+            sql.push('\'');
+        }
+        ValueRef::Blob(blob) => {
+            sql.push_str("x'");
+            for byte in blob {
+                let _ = write!(sql, "{:02x}", byte);
+            }
+            sql.push('\'');
+        }
+    }
+}
+
+impl SyncSqliteConnection {
+    /// Names of the tables in the main database, without SQLite's internal
+    /// `sqlite_*` tables.
+    pub fn tables(&self) -> Result<Vec<String>> {
+        let mut stmt = self.try_get()?.prepare(
+            "SELECT name FROM sqlite_master \
+             WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+             ORDER BY name",
+        )?;
+        let names = stmt.query_map([], |row| row.get(0))?;
+        names.collect()
+    }
+
+    /// The `CREATE TABLE` statement of a table, or `None` if there is no such
+    /// table.
+    pub fn table_sql(&self, name: &str) -> Result<Option<String>> {
+        let mut stmt = self
+            .try_get()?
+            .prepare("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?")?;
+        let mut rows = stmt.query([name])?;
+        match rows.next()? {
+            Some(row) => row.get(0).map(Some),
+            None => Result::Ok(None),
+        }
+    }
+
+    /// The statements creating the tables, indexes, views and triggers of the
+    /// main database, each followed by `;` and a newline. Tables come first
+    /// and each kind is in creation order, so running the dump on an empty
+    /// database re-creates the schema. The shadow tables of virtual tables
+    /// are left out, as creating the virtual tables creates them.
+    pub fn dump_schema(&self) -> Result<String> {
+        let mut dump = String::new();
+        self.for_each_schema_statement(|sql| {
+            dump.push_str(sql);
+            Result::Ok(())
+        })?;
+        Result::Ok(dump)
+    }
+
+    /// An `INSERT` statement, followed by `;` and a newline, for every row of
+    /// every table in the main database, then for the `AUTOINCREMENT`
+    /// counters in `sqlite_sequence`. Run after `dump_schema`, it fills the
+    /// re-created tables with the same data. Rowids are only kept where they
+    /// are an `INTEGER PRIMARY KEY` column. Generated columns are left out,
+    /// as they are computed again, and so are the shadow tables virtual
+    /// tables keep their content in, which inserting into the virtual tables
+    /// fills.
+    pub fn dump_data(&self) -> Result<String> {
+        let mut dump = String::new();
+        self.for_each_insert(|sql| {
+            dump.push_str(sql);
+            Result::Ok(())
+        })?;
+        Result::Ok(dump)
+    }
+
+    /// Writes `dump_schema` followed by `dump_data` to `w` one statement at a
+    /// time, without holding the whole dump in memory. Write errors become
+    /// `SQLITE_IOERR` failures.
+    pub fn dump_to_writer<W: io::Write>(&self, mut w: W) -> Result<()> {
+        self.for_each_schema_statement(|sql| w.write_all(sql.as_bytes()).map_err(write_error))?;
+        self.for_each_insert(|sql| w.write_all(sql.as_bytes()).map_err(write_error))?;
+        w.flush().map_err(write_error)
+    }
+
+    fn for_each_schema_statement<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let mut stmt = self.try_get()?.prepare(
+            "SELECT sql || ';' || char(10) FROM sqlite_master \
+             WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+             AND tbl_name NOT IN \
+             (SELECT name FROM pragma_table_list WHERE schema = 'main' AND type = 'shadow') \
+             ORDER BY CASE type WHEN 'table' THEN 0 WHEN 'index' THEN 1 \
+             WHEN 'view' THEN 2 ELSE 3 END, rowid",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            f(row.get_ref(0)?.as_str()?)?;
+        }
+        Result::Ok(())
+    }
+
+    fn for_each_insert<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let conn = self.try_get()?;
+        let mut sql = String::new();
+        let mut tables = conn.prepare(
+            "SELECT name FROM pragma_table_list \
+             WHERE schema = 'main' AND type IN ('table', 'virtual') \
+             AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+             ORDER BY name",
+        )?;
+        let tables = tables
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>>>()?;
+        let mut columns =
+            conn.prepare("SELECT name FROM pragma_table_xinfo(?) WHERE hidden = 0")?;
+        for table in tables {
+            let columns = columns
+                .query_map([&table], |row| row.get::<_, String>(0))?
+                .map(|name| name.map(|name| quote_identifier(&name)))
+                .collect::<Result<Vec<_>>>()?
+                .join(", ");
+            let table = quote_identifier(&table);
+            self.for_each_row_insert(&table, &columns, &mut sql, &mut f)?;
+        }
+
+        let has_sequence: bool = conn.query_row(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE name = 'sqlite_sequence'",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_sequence {
+            f("DELETE FROM sqlite_sequence;\n")?;
+            self.for_each_row_insert("sqlite_sequence", "name, seq", &mut sql, &mut f)?;
+        }
+        Result::Ok(())
+    }
+
+    // Passes an INSERT statement for every row of the columns of the table,
+    // both already quoted, to `f`.
+    fn for_each_row_insert<F>(
+        &self,
+        table: &str,
+        columns: &str,
+        sql: &mut String,
+        f: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let mut stmt = self
+            .try_get()?
+            .prepare(&format!("SELECT {} FROM {}", columns, table))?;
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            sql.clear();
+            let _ = write!(sql, "INSERT INTO {} ({}) VALUES (", table, columns);
+            for idx in 0..column_count {
+                if idx > 0 {
+                    sql.push_str(", ");
+                }
+                push_literal(sql, row.get_ref(idx)?);
+            }
+            sql.push_str(");\n");
+            f(sql)?;
+        }
+        Result::Ok(())
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_tables() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT);
+             CREATE TABLE orders (id INTEGER, user_id INTEGER);",
+        )
+        .unwrap();
+
+        assert_eq!(c1.tables().unwrap(), vec!["orders", "users"]);
+        assert_eq!(
+            c1.table_sql("orders").unwrap().unwrap(),
+            "CREATE TABLE orders (id INTEGER, user_id INTEGER)"
+        );
+        assert_eq!(
+            c1.table_sql("users").unwrap().unwrap(),
+            "CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT)"
+        );
+        assert_eq!(c1.table_sql("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_dump_schema() {
+        let schema = |c: &crate::SyncSqliteConnection| -> Vec<(String, String, String)> {
+            c.prepare(
+                "SELECT type, name, sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY name",
+            )
+            .unwrap()
+            .query_collect([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+        };
+
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT UNIQUE);
+             CREATE VIEW named AS SELECT name FROM users WHERE name IS NOT NULL;
+             CREATE TABLE orders (id INTEGER, user_id INTEGER REFERENCES users (id));
+             CREATE INDEX orders_user ON orders (user_id);
+             CREATE TRIGGER no_orphans AFTER DELETE ON users
+             BEGIN DELETE FROM orders WHERE user_id = old.id; END;
+             INSERT INTO users (name) VALUES ('a');",
+        )
+        .unwrap();
+
+        let dump = c1.dump_schema().unwrap();
+        assert!(!dump.contains("sqlite_sequence"));
+        let c2 = crate::SyncSqliteConnection::new().unwrap();
+        c2.execute_batch(&dump).unwrap();
+        assert_eq!(schema(&c1), schema(&c2));
+        assert_eq!(c2.dump_schema().unwrap(), dump);
+    }
+
+    #[test]
+    fn test_dump_data() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, i INTEGER, r REAL, s TEXT, b BLOB);
+             CREATE TABLE \"empty table\" (x INTEGER);
+             INSERT INTO t VALUES
+                 (1, -9223372036854775808, 0.1, 'it''s', x'00ff'),
+                 (2, NULL, 9e999, '', x''),
+                 (3, 7, -1.5e-300, NULL, NULL);",
+        )
+        .unwrap();
+
+        let c2 = crate::SyncSqliteConnection::new().unwrap();
+        let schema = c1.dump_schema().unwrap();
+        let data = c1.dump_data().unwrap();
+        c2.execute_batch(&schema).unwrap();
+        c2.execute_batch(&data).unwrap();
+        assert_eq!(c2.dump_data().unwrap(), data);
+        let reals: Vec<f64> = c2
+            .prepare("SELECT r FROM t ORDER BY id")
+            .unwrap()
+            .query_collect([], |row| row.get(0))
+            .unwrap();
+        assert_eq!(reals, vec![0.1, f64::INFINITY, -1.5e-300]);
+    }
+
+    #[test]
+    fn test_dump_to_writer() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, s TEXT);
+             CREATE INDEX t_s ON t (s);
+             INSERT INTO t VALUES (1, 'a'), (2, 'b;c');",
+        )
+        .unwrap();
+
+        let mut dump = Vec::new();
+        c1.dump_to_writer(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert_eq!(dump, c1.dump_schema().unwrap() + &c1.dump_data().unwrap());
+
+        let c2 = crate::SyncSqliteConnection::new().unwrap();
+        c2.execute_batch(&dump).unwrap();
+        assert_eq!(c2.dump_schema().unwrap(), c1.dump_schema().unwrap());
+        assert_eq!(c2.dump_data().unwrap(), c1.dump_data().unwrap());
+    }
+
+    #[test]
+    fn test_dump_data_reload() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT);
+             CREATE TABLE prices (
+                 net REAL,
+                 gross REAL GENERATED ALWAYS AS (net * 1.2) STORED,
+                 label TEXT AS ('net ' || net)
+             );
+             CREATE VIRTUAL TABLE docs USING fts5 (body);
+             INSERT INTO users (name) VALUES ('a'), ('b'), ('c');
+             DELETE FROM users WHERE name IN ('b', 'c');
+             INSERT INTO prices (net) VALUES (10), (2.5);
+             INSERT INTO docs VALUES ('hello world'), ('goodbye');",
+        )
+        .unwrap();
+
+        let data = c1.dump_data().unwrap();
+        assert!(!data.contains("docs_"));
+        assert!(!data.contains("gross"));
+        let c2 = crate::SyncSqliteConnection::new().unwrap();
+        c2.execute_batch(&c1.dump_schema().unwrap()).unwrap();
+        c2.execute_batch(&data).unwrap();
+        assert_eq!(c2.dump_data().unwrap(), data);
+
+        // The counter went past the deleted rows.
+        c2.execute("INSERT INTO users (name) VALUES ('d')", [])
+            .unwrap();
+        assert_eq!(c2.last_insert_rowid(), 4);
+        let gross: Vec<(f64, String)> = c2
+            .prepare("SELECT gross, label FROM prices ORDER BY net")
+            .unwrap()
+            .query_collect([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(
+            gross,
+            vec![(3.0, "net 2.5".to_owned()), (12.0, "net 10.0".to_owned())]
+        );
+        let found: String = c2
+            .query_row(
+                "SELECT body FROM docs WHERE docs MATCH 'hello'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(found, "hello world");
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use rusqlite::*;
+
+use crate::SyncSqliteConnection;
+
+/// A copy of a database taken by `SyncSqliteConnection::snapshot`.
+pub struct Snapshot<'conn> {
+    conn: &'conn SyncSqliteConnection,
+    copy: Connection,
+}
+
+impl<'conn> Snapshot<'conn> {
+    /// Puts the database back into the state it had when the snapshot was
+    /// taken. Since all threads share the database, this discards the
+    /// changes made through every thread's connection, not only the current
+    /// one. It fails while another connection is in a transaction.
+    pub fn restore(self) -> Result<()> {
+        let mut target = Connection::open_with_flags(&self.conn.uri, self.conn.flags)?;
+        backup::Backup::new(&self.copy, &mut target)?.run_to_completion(
+            1024,
+            Duration::from_millis(0),
+            None,
+        )?;
+        Result::Ok(())
+    }
+}
+
+impl SyncSqliteConnection {
+    /// Copies the main database into a private in-memory database, so that
+    /// tests can cheaply go back to a baseline with `Snapshot::restore`.
+    /// Fails with `SQLITE_MISUSE` for a `CacheMode::Private` handle, whose
+    /// threads each have their own database that a restore could not reach.
+    pub fn snapshot(&self) -> Result<Snapshot<'_>> {
+        if self.uri.contains("mode=memory") && !self.uri.contains("cache=shared") {
+            return Result::Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_MISUSE),
+                Some("snapshots need a database shared by all connections".to_owned()),
+            ));
+        }
+        let mut copy = Connection::open_in_memory()?;
+        backup::Backup::new(self.try_get()?, &mut copy)?.run_to_completion(
+            1024,
+            Duration::from_millis(0),
+            None,
+        )?;
+        Result::Ok(Snapshot { conn: self, copy })
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_snapshot_restore() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let snapshot = c1.snapshot().unwrap();
+        c1.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.execute("INSERT INTO t VALUES (2)", []).unwrap();
+            });
+        });
+        snapshot.restore().unwrap();
+
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_snapshot_empty() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+
+        let snapshot = c1.snapshot().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        snapshot.restore().unwrap();
+
+        assert!(c1.tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_private() {
+        use crate::CacheMode;
+
+        let c1 = crate::SyncSqliteConnection::new_with_cache(CacheMode::Private).unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let err = c1.snapshot().err().unwrap();
+        assert_eq!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::ApiMisuse)
+        );
+        assert_eq!(c1.tables().unwrap(), vec!["t".to_owned()]);
+    }
+}
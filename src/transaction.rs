@@ -0,0 +1,479 @@
+use std::ops::Deref;
+use std::time::Duration;
+
+use rusqlite::*;
+
+use crate::quote_identifier;
+use crate::{is_busy, SyncSqliteConnection};
+
+// How often in_transaction retries a closure that failed on a lock.
+const TRANSACTION_RETRIES: u32 = 10;
+
+pub struct SyncTransaction<'conn> {
+    conn: &'conn Connection,
+    // total_changes() of the connection when the transaction began.
+    total_changes: u64,
+    finished: bool,
+}
+
+impl<'conn> SyncTransaction<'conn> {
+    fn new(
+        conn: &'conn Connection,
+        behavior: TransactionBehavior,
+    ) -> Result<SyncTransaction<'conn>> {
+        let query = match behavior {
+            TransactionBehavior::Deferred => "BEGIN DEFERRED",
+            TransactionBehavior::Immediate => "BEGIN IMMEDIATE",
+            TransactionBehavior::Exclusive => "BEGIN EXCLUSIVE",
+            _ => "BEGIN",
+        };
+        conn.execute_batch(query)?;
+
+        Result::Ok(SyncTransaction {
+            conn,
+            total_changes: conn.total_changes(),
+            finished: false,
+        })
+    }
+
+    /// Commits the transaction and returns how many rows its INSERT, UPDATE
+    /// and DELETE statements changed, 0 if it only read. Changes undone by
+    /// `SyncSavepoint::rollback_to` are still counted. If the commit fails,
+    /// e.g. on a deferred foreign key violation, the transaction is rolled
+    /// back instead.
+    pub fn commit(mut self) -> Result<usize> {
+        self.conn.execute_batch("COMMIT")?;
+        self.finished = true;
+        Result::Ok((self.conn.total_changes() - self.total_changes) as usize)
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        self.finished = true;
+        self.conn.execute_batch("ROLLBACK")
+    }
+
+    pub fn savepoint(&self, name: &str) -> Result<SyncSavepoint<'_>> {
+        SyncSavepoint::new(self.conn, name)
+    }
+}
+
+impl<'conn> Deref for SyncTransaction<'conn> {
+    type Target = Connection;
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+
+impl<'conn> Drop for SyncTransaction<'conn> {
+    fn drop(&mut self) {
+        if !self.finished && !self.conn.is_autocommit() {
+            let _ignore = self.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+/// A named savepoint inside a `SyncTransaction`. Dropping it without calling
+/// `release` releases it, keeping its changes in the enclosing transaction.
+pub struct SyncSavepoint<'tx> {
+    conn: &'tx Connection,
+    name: String,
+    finished: bool,
+}
+
+impl<'tx> SyncSavepoint<'tx> {
+    fn new(conn: &'tx Connection, name: &str) -> Result<SyncSavepoint<'tx>> {
+        let name = quote_identifier(name);
+        conn.execute_batch(&format!("SAVEPOINT {}", name))?;
+
+        Result::Ok(SyncSavepoint {
+            conn,
+            name,
+            finished: false,
+        })
+    }
+
+    pub fn release(mut self) -> Result<()> {
+        self.finished = true;
+        self.conn.execute_batch(&format!("RELEASE {}", self.name))
+    }
+
+    /// Undoes everything done since the savepoint was created. The savepoint
+    /// itself stays open and can be rolled back to again.
+    pub fn rollback_to(&self) -> Result<()> {
+        self.conn
+            .execute_batch(&format!("ROLLBACK TO {}", self.name))
+    }
+}
+
+impl<'tx> Deref for SyncSavepoint<'tx> {
+    type Target = Connection;
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+
+impl<'tx> Drop for SyncSavepoint<'tx> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ignore = self.conn.execute_batch(&format!("RELEASE {}", self.name));
+        }
+    }
+}
+
+impl SyncSqliteConnection {
+    pub fn transaction(&self) -> Result<SyncTransaction<'_>> {
+        self.transaction_with_behavior(TransactionBehavior::Deferred)
+    }
+
+    /// Begins a transaction on the current thread's connection. `Immediate` and
+    /// `Exclusive` take the write lock up front, so a concurrent writer fails
+    /// when it begins instead of in the middle of its transaction.
+    pub fn transaction_with_behavior(
+        &self,
+        behavior: TransactionBehavior,
+    ) -> Result<SyncTransaction<'_>> {
+        SyncTransaction::new(self.try_get()?, behavior)
+    }
+
+    /// Runs `f` in a transaction on the current thread's connection, which is
+    /// committed if `f` returns `Ok` and rolled back if it returns `Err` or
+    /// panics. Unlike `in_transaction`, `f` runs once.
+    pub fn with_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&SyncTransaction<'_>) -> Result<T>,
+    {
+        let tx = self.transaction()?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Result::Ok(value)
+    }
+
+    /// Runs `f` in an immediate transaction, which is committed if `f`
+    /// returns `Ok` and rolled back if it returns `Err`. If `f` or the commit
+    /// fails because another connection holds a lock, the transaction is
+    /// rolled back and `f` runs again after a backoff, up to 10 times, so it
+    /// must not have side effects outside the database.
+    pub fn in_transaction<R, F>(&self, mut f: F) -> Result<R>
+    where
+        F: FnMut(&SyncTransaction<'_>) -> Result<R>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .and_then(|tx| {
+                    let value = f(&tx)?;
+                    tx.commit()?;
+                    Result::Ok(value)
+                });
+            match result {
+                Result::Err(ref err) if is_busy(err) && attempt < TRANSACTION_RETRIES => {
+                    std::thread::sleep(Duration::from_millis(1 << attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs the statements in one transaction, so either all of them take
+    /// effect or, if any fails, none do.
+    pub fn execute_batch_atomic(&self, sql: &str) -> Result<()> {
+        let tx = self.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.commit().map(|_| ())
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_immediate_blocks_other_writer() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let tx = c1
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .unwrap();
+        tx.execute("INSERT INTO t VALUES (1)", []).unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let err = c1.execute("INSERT INTO t VALUES (2)", []).unwrap_err();
+                match err {
+                    rusqlite::Error::SqliteFailure(e, _) => assert!(
+                        e.code == rusqlite::ErrorCode::DatabaseBusy
+                            || e.code == rusqlite::ErrorCode::DatabaseLocked
+                    ),
+                    _ => panic!("unexpected error: {}", err),
+                }
+            });
+        });
+
+        tx.commit().unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                c1.execute("INSERT INTO t VALUES (2)", []).unwrap();
+            });
+        });
+
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_immediate_transactions_serialize() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE counter (n INTEGER); INSERT INTO counter VALUES (0);")
+            .unwrap();
+
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let tx = loop {
+                            match c1
+                                .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                            {
+                                Ok(tx) => break tx,
+                                Err(rusqlite::Error::SqliteFailure(e, _))
+                                    if e.code == rusqlite::ErrorCode::DatabaseBusy
+                                        || e.code == rusqlite::ErrorCode::DatabaseLocked =>
+                                {
+                                    std::thread::yield_now()
+                                }
+                                Err(e) => panic!("unexpected error: {}", e),
+                            }
+                        };
+
+                        let n: i64 = tx
+                            .query_row("SELECT n FROM counter", [], |row| row.get(0))
+                            .unwrap();
+                        tx.execute("UPDATE counter SET n = ?", [n + 1]).unwrap();
+                        tx.commit().unwrap();
+                    }
+                });
+            }
+        });
+
+        let n: i64 = c1
+            .query_row("SELECT n FROM counter", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 100);
+    }
+
+    #[test]
+    fn test_savepoint_rollback_to() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let tx = c1.transaction().unwrap();
+        tx.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        {
+            let sp = tx.savepoint("setup").unwrap();
+            sp.execute("INSERT INTO t VALUES (2)", []).unwrap();
+            sp.rollback_to().unwrap();
+        }
+        tx.commit().unwrap();
+
+        let values: Vec<i64> = c1
+            .prepare("SELECT x FROM t")
+            .unwrap()
+            .query_map(rusqlite::params![], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(values, vec![1]);
+    }
+
+    #[test]
+    fn test_transaction_rollback_on_drop() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        {
+            let tx = c1.transaction().unwrap();
+            tx.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        }
+
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_failed_commit_rolls_back() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch(
+            "CREATE TABLE parent (id INTEGER PRIMARY KEY);
+             CREATE TABLE child (parent_id INTEGER
+                 REFERENCES parent (id) DEFERRABLE INITIALLY DEFERRED);
+             PRAGMA foreign_keys = ON;",
+        )
+        .unwrap();
+
+        let tx = c1.transaction().unwrap();
+        tx.execute("INSERT INTO child VALUES (1)", []).unwrap();
+        assert!(tx.commit().is_err());
+        assert!(c1.is_autocommit());
+
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM child", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+        let tx = c1.transaction().unwrap();
+        tx.execute("INSERT INTO parent VALUES (1)", []).unwrap();
+        tx.execute("INSERT INTO child VALUES (1)", []).unwrap();
+        assert_eq!(tx.commit().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_commit_changes() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (0);")
+            .unwrap();
+
+        let tx = c1.transaction().unwrap();
+        tx.execute("INSERT INTO t VALUES (1)", []).unwrap();
+        tx.execute("INSERT INTO t VALUES (2)", []).unwrap();
+        assert_eq!(tx.commit().unwrap(), 2);
+
+        let tx = c1.transaction().unwrap();
+        let count: i64 = tx
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(tx.commit().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_in_transaction() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE counter (n INTEGER); INSERT INTO counter VALUES (0);")
+            .unwrap();
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        c1.in_transaction(|tx| {
+                            let n: i64 =
+                                tx.query_row("SELECT n FROM counter", [], |row| row.get(0))?;
+                            tx.execute("UPDATE counter SET n = ?", [n + 1])
+                        })
+                        .unwrap();
+                    }
+                });
+            }
+        });
+
+        let n: i64 = c1
+            .query_row("SELECT n FROM counter", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 40);
+    }
+
+    #[test]
+    fn test_in_transaction_rollback() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        let result: rusqlite::Result<()> = c1.in_transaction(|tx| {
+            tx.execute("INSERT INTO t VALUES (1)", [])?;
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        });
+        assert_eq!(result, Err(rusqlite::Error::QueryReturnedNoRows));
+
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_in_transaction_retries_failed_commit() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let insert = c1.prepare("INSERT INTO t VALUES (1) RETURNING x").unwrap();
+
+        let mut attempts = 0;
+        let mut running = None;
+        c1.in_transaction(|_| {
+            attempts += 1;
+            // Resets the statement left running by the previous attempt.
+            running = None;
+            let mut rows = insert.query([])?;
+            rows.next()?;
+            if attempts == 1 {
+                // While a write statement is running, COMMIT fails with
+                // SQLITE_BUSY.
+                running = Some(rows);
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(attempts, 2);
+
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_with_transaction() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+        let count = || -> i64 {
+            c1.query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        let result = c1.with_transaction(|tx| {
+            tx.execute("INSERT INTO t VALUES (1)", [])?;
+            tx.execute("INSERT INTO missing VALUES (2)", [])
+        });
+        assert!(result.is_err());
+        assert_eq!(count(), 0);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            c1.with_transaction(|tx| -> rusqlite::Result<()> {
+                tx.execute("INSERT INTO t VALUES (1)", [])?;
+                panic!("in transaction");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(count(), 0);
+
+        let inserted = c1
+            .with_transaction(|tx| tx.execute("INSERT INTO t VALUES (1)", []))
+            .unwrap();
+        assert_eq!(inserted, 1);
+        assert_eq!(count(), 1);
+    }
+
+    #[test]
+    fn test_execute_batch_atomic() {
+        let c1 = crate::SyncSqliteConnection::new().unwrap();
+        c1.execute_batch("CREATE TABLE t (x INTEGER)").unwrap();
+
+        assert!(c1
+            .execute_batch_atomic("INSERT INTO t VALUES (1); INSERT INTO missing VALUES (2);")
+            .is_err());
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        c1.execute_batch_atomic("INSERT INTO t VALUES (1); INSERT INTO t VALUES (2);")
+            .unwrap();
+        let count: i64 = c1
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+}
@@ -0,0 +1,157 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::*;
+
+use crate::{open_shared, shared_uri, Initializer, SyncSqliteConnection};
+
+/// A database with other in-memory databases attached to it under aliases,
+/// so that one statement can use tables of several of them, e.g.
+/// `SELECT * FROM users.t JOIN orders.t USING (id)`. It derefs to the handle
+/// of the main database. The attached databases are kept alive by the
+/// connections of the workspace and freed with it.
+pub struct SyncSqliteWorkspace {
+    conn: SyncSqliteConnection,
+    aliases: Vec<String>,
+    // The initializers attaching the databases, in the order of `aliases`.
+    attachments: Vec<Initializer>,
+}
+
+impl SyncSqliteWorkspace {
+    /// Opens a new main database and attaches a new, empty database under
+    /// each of the aliases.
+    pub fn new(aliases: &[&str]) -> Result<Self> {
+        let mut this = SyncSqliteWorkspace {
+            conn: SyncSqliteConnection::new_unique()?,
+            aliases: Vec::new(),
+            attachments: Vec::new(),
+        };
+        for alias in aliases {
+            this.attach(alias)?;
+        }
+        Result::Ok(this)
+    }
+
+    /// Attaches a new, empty database under `alias` to the connection of
+    /// every thread.
+    pub fn attach(&mut self, alias: &str) -> Result<()> {
+        let uri = shared_uri(&self.attached_name(alias));
+        let alias = alias.to_owned();
+        let attached = alias.clone();
+        let attachment: Initializer = Arc::new(move |conn| {
+            conn.execute("ATTACH DATABASE ? AS ?", [&uri, &attached])
+                .map(|_| ())
+        });
+        self.conn.add_initializer(attachment.clone())?;
+        self.aliases.push(alias);
+        self.attachments.push(attachment);
+        Result::Ok(())
+    }
+
+    /// Copies the main database and every attached one into new databases
+    /// and returns a workspace over the copies, with the same aliases. Like
+    /// `SyncSqliteConnection::fork`, it shares no data with the original.
+    pub fn fork(&self) -> Result<SyncSqliteWorkspace> {
+        let mut fork = SyncSqliteWorkspace {
+            conn: self.conn.fork_without(&self.attachments)?,
+            aliases: Vec::new(),
+            attachments: Vec::new(),
+        };
+        for alias in &self.aliases {
+            fork.attach(alias)?;
+            let mut target = open_shared(&fork.attached_name(alias))?;
+            backup::Backup::new_with_names(
+                self.conn.try_get()?,
+                alias.as_str(),
+                &mut target,
+                "main",
+            )?
+            .run_to_completion(1024, Duration::from_millis(0), None)?;
+        }
+        Result::Ok(fork)
+    }
+
+    // The name of the shared in-memory database attached under `alias`.
+    fn attached_name(&self, alias: &str) -> String {
+        format!("{}_{}", self.conn.name(), alias)
+    }
+
+    /// The aliases of the attached databases, in the order they were attached.
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+}
+
+impl Deref for SyncSqliteWorkspace {
+    type Target = SyncSqliteConnection;
+
+    fn deref(&self) -> &SyncSqliteConnection {
+        &self.conn
+    }
+}
+
+mod test {
+
+    #[test]
+    fn test_workspace() {
+        let workspace = crate::SyncSqliteWorkspace::new(&["users", "orders"]).unwrap();
+        assert_eq!(workspace.aliases(), ["users", "orders"]);
+        workspace
+            .execute_batch(
+                "CREATE TABLE users.t (id INTEGER PRIMARY KEY, name TEXT);
+                 CREATE TABLE orders.t (user_id INTEGER, total INTEGER);
+                 INSERT INTO users.t VALUES (1, 'a'), (2, 'b');
+                 INSERT INTO orders.t VALUES (1, 10), (1, 5), (2, 7);",
+            )
+            .unwrap();
+
+        let totals = || -> Vec<(String, i64)> {
+            workspace
+                .prepare(
+                    "SELECT name, sum(total) FROM users.t JOIN orders.t ON user_id = id \
+                     GROUP BY name ORDER BY name",
+                )
+                .unwrap()
+                .query_collect([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+        };
+
+        let here = totals();
+        let there = std::thread::scope(|s| s.spawn(totals).join().unwrap());
+        assert_eq!(here, vec![("a".to_owned(), 15), ("b".to_owned(), 7)]);
+        assert_eq!(here, there);
+    }
+
+    #[test]
+    fn test_workspace_fork() {
+        let workspace = crate::SyncSqliteWorkspace::new(&["users"]).unwrap();
+        workspace
+            .execute_batch(
+                "CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);
+                 CREATE TABLE users.t (id INTEGER); INSERT INTO users.t VALUES (1);",
+            )
+            .unwrap();
+
+        let fork = workspace.fork().unwrap();
+        assert_eq!(fork.aliases(), ["users"]);
+        fork.execute_batch("INSERT INTO t VALUES (2); INSERT INTO users.t VALUES (2);")
+            .unwrap();
+
+        let count = |workspace: &crate::SyncSqliteWorkspace, table: &str| -> i64 {
+            workspace
+                .query_row(&format!("SELECT count(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(count(&workspace, "t"), 1);
+                assert_eq!(count(&workspace, "users.t"), 1);
+                assert_eq!(count(&fork, "t"), 2);
+                assert_eq!(count(&fork, "users.t"), 2);
+            });
+        });
+    }
+}